@@ -0,0 +1,170 @@
+use crate::object::{Object, Reader, Writer};
+
+// Static archives of object files
+//
+// An archive bundles objects with an index of the global symbols each one
+// defines. The linker only reads the index to find which members it needs, so a
+// library of routines only adds the routines a program actually calls.
+//
+// Everything is big endian, strings are a u16 length followed by UTF-8 bytes.
+//
+// | Field          | Contents                                                 |
+// | Magic          | "DBVA"                                                   |
+// | Format version | u16                                                      |
+// | Symbol index   | u32 count, then (name, u32 member)                       |
+// | Members        | u32 count, then (name, u32 length, object file contents) |
+
+pub const MAGIC: &[u8; 4] = b"DBVA";
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub members: Vec<Object>,
+    pub index: Vec<(String, usize)>, // Global symbol, member defining it
+}
+
+impl Archive {
+    /// Bundles objects into an archive, failing if two of them export the same
+    /// symbol since the index couldn't tell which one to use.
+    pub fn new(members: Vec<Object>) -> Result<Self, String> {
+        let mut index: Vec<(String, usize)> = Vec::new();
+        for (member_index, member) in members.iter().enumerate() {
+            for symbol in member.symbols.iter().filter(|symbol| symbol.global) {
+                if let Some((_, other)) = index.iter().find(|(name, _)| *name == symbol.name) {
+                    return Err(format!("Duplicate symbol {} defined in {} and {}", symbol.name, members[*other].name, member.name));
+                }
+
+                index.push((symbol.name.clone(), member_index));
+            }
+        }
+
+        Ok(Self { members, index })
+    }
+
+    /// Returns the member that defines the symbol.
+    pub fn member_for(&self, symbol: &str) -> Option<usize> {
+        self.index.iter().find(|(name, _)| name == symbol).map(|(_, member)| *member)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.bytes(MAGIC);
+        writer.u16(FORMAT_VERSION);
+
+        writer.u32(self.index.len() as u32);
+        for (symbol, member) in &self.index {
+            writer.string(symbol);
+            writer.u32(*member as u32);
+        }
+
+        writer.u32(self.members.len() as u32);
+        for member in &self.members {
+            let bytes = member.to_bytes();
+            writer.string(&member.name);
+            writer.u32(bytes.len() as u32);
+            writer.bytes(&bytes);
+        }
+
+        writer.output
+    }
+
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes, position: 0 };
+        if !is_archive(bytes) {
+            return Err(format!("{} is not an archive", name));
+        }
+        reader.bytes(MAGIC.len())?;
+
+        let format_version = reader.u16()?;
+        if format_version != FORMAT_VERSION {
+            return Err(format!("{}: unsupported archive format version {}", name, format_version));
+        }
+
+        let mut index = Vec::new();
+        for _ in 0..reader.u32()? {
+            index.push((reader.string()?, reader.u32()? as usize));
+        }
+
+        let mut members = Vec::new();
+        for _ in 0..reader.u32()? {
+            let member_name = format!("{}({})", name, reader.string()?);
+            let length = reader.u32()? as usize;
+            members.push(Object::from_bytes(&member_name, reader.bytes(length)?)?);
+        }
+
+        if let Some((symbol, _)) = index.iter().find(|(_, member)| *member >= members.len()) {
+            return Err(format!("{}: symbol {} is in a missing member", name, symbol));
+        }
+
+        Ok(Self { members, index })
+    }
+}
+
+/// Returns true if the bytes start with the archive's magic.
+pub fn is_archive(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use crate::control::lower_control_flow;
+    use crate::labels::scope_labels;
+    use crate::lexer::Lexer;
+    use crate::parser::parse;
+    use crate::program::Addressing;
+
+    fn object(file: &str, source: &str) -> Object {
+        let statements = lower_control_flow(&scope_labels(&parse(&Lexer::new(file).tokenize(source)), false));
+        Object::assemble(&Assembly::new(file, &statements, Addressing::Byte)).unwrap()
+    }
+
+    fn library() -> Archive {
+        Archive::new(vec![
+            object("square.asm", ".global square\nsquare: MUL Ra, Ra, Ra\n    RET\n"),
+            object("minmax.asm", ".global min, max\nmin: RET\nmax: RET\n"),
+        ]).unwrap()
+    }
+
+    #[test]
+    fn index_lists_every_global() {
+        let archive = library();
+
+        assert_eq!(archive.member_for("SQUARE"), Some(0));
+        assert_eq!(archive.member_for("MIN"), Some(1));
+        assert_eq!(archive.member_for("MAX"), Some(1));
+        assert_eq!(archive.member_for("CUBE"), None);
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let archive = library();
+        let read = Archive::from_bytes("lib.a", &archive.to_bytes()).unwrap();
+
+        assert_eq!(read.index, archive.index);
+        let names: Vec<&str> = read.members.iter().map(|member| member.name.as_str()).collect();
+        assert_eq!(names, vec!["lib.a(square.asm)", "lib.a(minmax.asm)"]);
+        for (read, original) in read.members.iter().zip(&archive.members) {
+            assert_eq!(read.to_bytes(), original.to_bytes());
+        }
+    }
+
+    #[test]
+    fn duplicate_globals_are_rejected() {
+        let err = Archive::new(vec![
+            object("a.asm", ".global square\nsquare: RET\n"),
+            object("b.asm", ".global square\nsquare: RET\n"),
+        ]).unwrap_err();
+        assert_eq!(err, "Duplicate symbol SQUARE defined in a.asm and b.asm");
+    }
+
+    #[test]
+    fn bad_archives_are_rejected() {
+        assert_eq!(Archive::from_bytes("lib.a", b"DBVO").unwrap_err(), "lib.a is not an archive");
+
+        let mut bytes = library().to_bytes();
+        bytes[5] = 2;
+        assert_eq!(Archive::from_bytes("lib.a", &bytes).unwrap_err(), "lib.a: unsupported archive format version 2");
+    }
+}
@@ -0,0 +1,394 @@
+use crate::ast::{ASTNode, Directive, DirectiveKind, Expr, Instruction, Operand, Span, Statement};
+use crate::program::Addressing;
+
+// Assembly passes
+//
+// Constants from `.struct` and `.enum` come first: they don't depend on any
+// address, so every use of one is replaced by its value before anything is
+// sized. The layout pass walks the statements once to size every section and give each
+// label its address. The lowering pass then resolves operands against those
+// addresses and turns instructions into `ASTNode`s and data directives into
+// words. Addresses are relative to the start of their section, the linker decides
+// where sections end up. Label names arrive already scoped and normalized by
+// src/labels.rs, so they're compared as they are.
+
+/// A section of the source file. Addresses inside it start at 0.
+#[derive(Debug, Clone)]
+pub struct AsmSection {
+    pub name: String,
+    pub origin: Option<u32>, // Fixed address set by `.org`, otherwise placed by the linker
+    pub size: u32,           // Size in addresses
+}
+
+impl AsmSection {
+    /// `.bss` sections only reserve space, nothing in them is written to the output.
+    pub fn is_bss(&self) -> bool {
+        self.name == ".bss" || self.name.starts_with(".bss.")
+    }
+}
+
+/// A word that holds the address of a symbol, filled in by the linker.
+#[derive(Debug, Clone)]
+pub struct AsmRelocation {
+    pub section: usize,
+    pub offset: u32,
+    pub symbol: String,
+}
+
+/// Everything assembled from one source file.
+pub struct Assembly {
+    pub file: String,
+    pub addressing: Addressing,
+    pub sections: Vec<AsmSection>,              // Sections in the order they first appear
+    pub labels: Vec<(String, usize, u32, Span)>, // Label name, section, address, definition
+    pub entry: Option<String>,                  // Label given by `.entry`
+    pub globals: Vec<(String, Span)>,           // Labels exported with `.global`, and where
+    pub externs: Vec<String>,                   // Labels imported with `.extern`
+    pub procedures: Vec<(String, u32)>,         // Label and size of every `.proc`
+    pub constants: Vec<(String, u32, Span)>,    // Name, value and definition of `.struct` and `.enum` members
+    pub nodes: Vec<ASTNode>,                    // Lowered instructions
+    pub data: Vec<(usize, u32, Span, Vec<u32>)>, // Section, address, definition and words from `.word` and `.space`
+    pub relocations: Vec<AsmRelocation>,
+    pub double_words: Vec<(usize, u32)>,        // Section and address of every 64-bit value, high word first
+}
+
+impl Assembly {
+    pub fn new(file: &str, statements: &[Statement], addressing: Addressing) -> Self {
+        let mut assembly = Assembly {
+            file: file.to_string(),
+            addressing,
+            sections: vec![AsmSection { name: String::from(".text"), origin: None, size: 0 }],
+            labels: Vec::new(),
+            entry: None,
+            globals: Vec::new(),
+            externs: Vec::new(),
+            procedures: Vec::new(),
+            constants: Vec::new(),
+            nodes: Vec::new(),
+            data: Vec::new(),
+            relocations: Vec::new(),
+            double_words: Vec::new(),
+        };
+
+        let statements = assembly.define_constants(statements);
+        let placement = assembly.layout(&statements);
+        assembly.lower(&statements, &placement);
+        assembly
+    }
+
+    /// Gives every `.struct` and `.enum` member its value and replaces the
+    /// constants used by instructions and `.word` with their values.
+    fn define_constants(&mut self, statements: &[Statement]) -> Vec<Statement> {
+        let instruction_size = self.addressing.instruction_size();
+        let mut next = 0; // Offset of the next field, or value of the next enum member
+
+        for statement in statements {
+            let Statement::Directive(directive) = statement else { continue };
+            let mut define = |name: &str, value: u32| {
+                if let Some((_, _, first)) = self.constants.iter().find(|(existing, _, _)| existing == name) {
+                    panic!("{}: Duplicate constant {}, first defined at {}", directive.span, name, first);
+                }
+                self.constants.push((name.to_string(), value, directive.span.clone()));
+            };
+
+            match &directive.kind {
+                DirectiveKind::Struct(_) | DirectiveKind::Enum(_) => next = 0,
+                DirectiveKind::Field(name, words) => {
+                    define(name, next);
+                    next += words * instruction_size;
+                },
+                DirectiveKind::EndStruct(name) => define(name, next),
+                DirectiveKind::EnumMember(name, value) => {
+                    let value = value.unwrap_or(next);
+                    define(name, value);
+                    next = value.wrapping_add(1);
+                },
+                _ => {},
+            }
+        }
+
+        let value = |expr: &mut Expr| {
+            if let Expr::Symbol(name) = expr {
+                if let Some((_, value, _)) = self.constants.iter().find(|(constant, _, _)| constant == name) {
+                    *expr = Expr::Number(*value);
+                }
+            }
+        };
+
+        statements.iter().map(|statement| {
+            let mut statement = statement.clone();
+            match &mut statement {
+                Statement::Instruction(instruction) => {
+                    for operand in &mut instruction.operands {
+                        if let Operand::Immediate(expr) | Operand::BaseOffset(_, expr) = operand {
+                            value(expr);
+                        }
+                    }
+                },
+                Statement::Directive(Directive { kind: DirectiveKind::Word(values), .. }) => values.iter_mut().for_each(value),
+                _ => {},
+            }
+            statement
+        }).collect()
+    }
+
+    /// Sizes the sections and places the labels. Returns the section and address
+    /// of every statement.
+    fn layout(&mut self, statements: &[Statement]) -> Vec<(usize, u32)> {
+        let instruction_size = self.addressing.instruction_size();
+        let mut current = 0;
+        let mut placement = Vec::new();
+        let mut open_procedures: Vec<(String, usize, u32)> = Vec::new(); // Name, section, start
+
+        for statement in statements {
+            placement.push((current, self.sections[current].size));
+
+            match statement {
+                Statement::Label(label) => self.define_label(&label.name, current, &label.span),
+                Statement::Instruction(instruction) => {
+                    if self.sections[current].is_bss() {
+                        panic!("{}: Only .space can be used in {}", instruction.span, self.sections[current].name);
+                    }
+                    self.sections[current].size += instruction.words() * instruction_size;
+                },
+                Statement::Directive(directive) => match &directive.kind {
+                    // `.org` keeps the name of the section it was used in, so `.org`
+                    // inside `.data` still puts data there
+                    DirectiveKind::Org(origin) => {
+                        let name = self.sections[current].name.clone();
+                        self.sections.push(AsmSection { name, origin: Some(*origin), size: 0 });
+                        current = self.sections.len() - 1;
+                    },
+                    DirectiveKind::Section(name) => {
+                        let name = name.to_lowercase();
+                        current = match self.sections.iter().position(|section| section.name == name && section.origin.is_none()) {
+                            Some(index) => index,
+                            None => {
+                                self.sections.push(AsmSection { name, origin: None, size: 0 });
+                                self.sections.len() - 1
+                            },
+                        };
+                    },
+                    DirectiveKind::Word(values) => {
+                        if self.sections[current].is_bss() {
+                            panic!("{}: Only .space can be used in {}", directive.span, self.sections[current].name);
+                        }
+                        self.sections[current].size += values.len() as u32 * instruction_size;
+                    },
+                    DirectiveKind::DWord(values) => {
+                        if self.sections[current].is_bss() {
+                            panic!("{}: Only .space can be used in {}", directive.span, self.sections[current].name);
+                        }
+                        self.sections[current].size += values.len() as u32 * 2 * instruction_size;
+                    },
+                    DirectiveKind::Space(bytes) => self.sections[current].size += bytes / 4 * instruction_size,
+                    DirectiveKind::Entry(label) => {
+                        if let Some(entry) = &self.entry {
+                            panic!("{}: Entry point is already set to {}", directive.span, entry);
+                        }
+                        self.entry = Some(label.clone());
+                    },
+                    DirectiveKind::Global(names) => self.globals.extend(names.iter().map(|name| (name.clone(), directive.span.clone()))),
+                    DirectiveKind::Extern(names) => self.externs.extend(names.iter().cloned()),
+                    DirectiveKind::Proc(name) => {
+                        self.define_label(name, current, &directive.span);
+                        open_procedures.push((name.clone(), current, self.sections[current].size));
+                    },
+                    // Scoping already checked every `.endproc` has a `.proc`
+                    DirectiveKind::EndProc => {
+                        let (name, section, start) = open_procedures.pop().unwrap();
+                        if section != current {
+                            panic!("{}: .endproc must be in the same section as .proc {}", directive.span, name);
+                        }
+                        self.procedures.push((name, self.sections[current].size - start));
+                    },
+                    // Register names are already resolved by the parser
+                    DirectiveKind::Req(_, _) | DirectiveKind::Unreq(_) => {},
+                    // Already turned into constants
+                    DirectiveKind::Struct(_) | DirectiveKind::Field(_, _) | DirectiveKind::EndStruct(_)
+                        | DirectiveKind::Enum(_) | DirectiveKind::EnumMember(_, _) | DirectiveKind::EndEnum => {},
+                    // Lowered to instructions and labels by src/control.rs
+                    DirectiveKind::If(_) | DirectiveKind::Else | DirectiveKind::EndIf | DirectiveKind::While(_)
+                        | DirectiveKind::EndWhile | DirectiveKind::Break | DirectiveKind::Continue => {},
+                },
+            }
+        }
+
+        placement
+    }
+
+    /// Gives a label the current address of the section.
+    fn define_label(&mut self, label: &str, section: usize, span: &Span) {
+        if let Some((_, _, constant)) = self.constants.iter().find(|(name, _, _)| name == label) {
+            panic!("{}: Label {} has the same name as the constant defined at {}", span, label, constant);
+        }
+        if let Some((_, _, _, first)) = self.labels.iter().find(|(existing, _, _, _)| existing == label) {
+            panic!("{}: Duplicate label {}, first defined at {}", span, label, first);
+        }
+        self.labels.push((label.to_string(), section, self.sections[section].size, span.clone()));
+    }
+
+    /// Resolves operands and data now that every label has an address.
+    fn lower(&mut self, statements: &[Statement], placement: &[(usize, u32)]) {
+        let instruction_size = self.addressing.instruction_size();
+
+        for (statement, (section, address)) in statements.iter().zip(placement) {
+            match statement {
+                Statement::Instruction(instruction) => {
+                    let node = self.lower_instruction(instruction, *section, *address);
+                    if let Some(label) = &node.label {
+                        self.relocations.push(AsmRelocation { section: *section, offset: address + instruction_size, symbol: label.clone() });
+                    }
+                    self.nodes.push(node);
+                },
+                Statement::Directive(directive) => match &directive.kind {
+                    DirectiveKind::Word(values) => {
+                        let mut words = Vec::new();
+                        for (index, value) in values.iter().enumerate() {
+                            if let Expr::Symbol(symbol) = value {
+                                let offset = address + index as u32 * instruction_size;
+                                self.relocations.push(AsmRelocation { section: *section, offset, symbol: symbol.clone() });
+                            }
+                            words.push(self.resolve(value, &directive.span));
+                        }
+                        self.data.push((*section, *address, directive.span.clone(), words));
+                    },
+                    // Stored high word first, the linker swaps them for little endian
+                    DirectiveKind::DWord(values) => {
+                        let mut words = Vec::new();
+                        for (index, value) in values.iter().enumerate() {
+                            self.double_words.push((*section, address + index as u32 * 2 * instruction_size));
+                            words.push((value >> 32) as u32);
+                            words.push(*value as u32);
+                        }
+                        self.data.push((*section, *address, directive.span.clone(), words));
+                    },
+                    DirectiveKind::Space(bytes) => {
+                        self.data.push((*section, *address, directive.span.clone(), vec![0; *bytes as usize / 4]));
+                    },
+                    _ => {},
+                },
+                Statement::Label(_) => {},
+            }
+        }
+    }
+
+    fn lower_instruction(&self, instruction: &Instruction, section: usize, address: u32) -> ASTNode {
+        let mut args = Vec::new();
+        for operand in &instruction.operands {
+            match operand {
+                Operand::Register(register) | Operand::Indirect(register) => args.push(register.0),
+                Operand::Immediate(value) => args.push(self.resolve(value, &instruction.span)),
+                Operand::BaseOffset(register, offset) => {
+                    args.push(register.0);
+                    args.push(self.resolve(offset, &instruction.span));
+                },
+            }
+        }
+
+        let mut node = ASTNode::new(instruction.op_code, instruction.mode(), args);
+        node.section = section;
+        node.address = address;
+        node.span = instruction.span.clone();
+        node.expansion = instruction.expansion.clone();
+        if let Some(Expr::Symbol(symbol)) = instruction.value() {
+            node.label = Some(symbol.clone());
+        }
+        // Matches the size the layout pass gave it, labels always get the extension word
+        node.extended = instruction.words() == 2;
+
+        node
+    }
+
+    /// The value of an expression. Labels give their section-relative address,
+    /// which the linker fixes up.
+    fn resolve(&self, value: &Expr, span: &Span) -> u32 {
+        match value {
+            Expr::Number(value) => *value,
+            Expr::Symbol(symbol) => match self.get_label_address(symbol) {
+                Some(address) => address,
+                // Filled in by the linker
+                None if self.is_extern(symbol) => 0,
+                None => panic!("{}: Undefined label: {}", span, symbol),
+            },
+        }
+    }
+
+    /// Returns true if the label was declared with `.extern`.
+    pub fn is_extern(&self, label: &str) -> bool {
+        self.externs.iter().any(|name| name == label)
+    }
+
+    /// Returns the address of a label, relative to the start of its section.
+    pub fn get_label_address(&self, label: &str) -> Option<u32> {
+        self.get_label(label).map(|(_, label_address)| label_address)
+    }
+
+    /// Returns the section and section-relative address of a label.
+    pub fn get_label(&self, label: &str) -> Option<(usize, u32)> {
+        self.labels.iter()
+            .find(|(name, _, _, _)| name == label)
+            .map(|(_, section, address, _)| (*section, *address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::labels::scope_labels;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::parser::parse;
+
+    fn assemble(source: &str, addressing: Addressing) -> Assembly {
+        Assembly::new("test.asm", &scope_labels(&parse(&Lexer::new("test.asm").tokenize(source)), false), addressing)
+    }
+
+    fn words(source: &str) -> Vec<u32> {
+        Object::assemble(&assemble(source, Addressing::Byte)).unwrap().sections[0].words.clone()
+    }
+
+    const LAYOUTS: &str = concat!(
+        ".struct Point\n",
+        "    x .word\n",
+        "    y .word 2\n",
+        "    name .space 8\n",
+        ".ends\n",
+        ".enum Color\n",
+        "    RED\n",
+        "    GREEN = 5\n",
+        "    BLUE\n",
+        ".endenum\n",
+    );
+
+    fn constants(assembly: &Assembly) -> Vec<(&str, u32)> {
+        assembly.constants.iter().map(|(name, value, _)| (name.as_str(), *value)).collect()
+    }
+
+    #[test]
+    fn members_count_in_address_units() {
+        assert_eq!(constants(&assemble(LAYOUTS, Addressing::Byte)), vec![
+            ("POINT.X", 0), ("POINT.Y", 4), ("POINT.NAME", 12), ("POINT.SIZE", 20),
+            ("COLOR.RED", 0), ("COLOR.GREEN", 5), ("COLOR.BLUE", 6),
+        ]);
+
+        // Enum values stay the same, only structure offsets depend on the addressing
+        assert_eq!(constants(&assemble(LAYOUTS, Addressing::Word)), vec![
+            ("POINT.X", 0), ("POINT.Y", 2), ("POINT.NAME", 6), ("POINT.SIZE", 10),
+            ("COLOR.RED", 0), ("COLOR.GREEN", 5), ("COLOR.BLUE", 6),
+        ]);
+    }
+
+    #[test]
+    fn constants_are_replaced_by_their_values() {
+        let with_names = words(&(String::from(LAYOUTS) + "LD Ra, [Rb + Point.name]\nSET Rc, Color.BLUE\nADD Rp, Rp, Point.size\n.word Color.GREEN\n"));
+        let with_numbers = words("LD Ra, [Rb + 12]\nSET Rc, 6\nADD Rp, Rp, 20\n.word 5\n");
+        assert_eq!(with_names, with_numbers);
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:3:1: Duplicate constant POINT.X, first defined at test.asm:2:5")]
+    fn duplicate_member_panics() {
+        assemble(".struct Point\n    x .word\nx .word\n.ends\n", Addressing::Byte);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::instructions::{Instructions, InstructionMode};
+use crate::utils::byte_to_register;
+
+// Abstract syntax tree
+//
+// The parser turns each line into statements: a label, an instruction or a
+// directive. Operands keep their kind and symbols keep their names, so passes
+// like layout and label resolution work on the structure of the program. Only
+// once every address is known are instructions lowered to `ASTNode`s, which
+// hold the numbers that get encoded.
+
+/// Where something was written in the source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,   // Starts at 1
+    pub column: usize, // Starts at 1, counted in characters
+    pub length: usize, // In characters
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// A register number (0 - 15).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Reg(pub u32);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(u32),
+    Symbol(String), // Label, resolved to its address
+}
+
+impl Expr {
+    /// Whether the value needs an extension word. Symbols always get one since
+    /// their address isn't known until the layout is done, or even until linking.
+    pub fn is_wide(&self) -> bool {
+        match self {
+            Expr::Number(value) => *value > 0xF,
+            Expr::Symbol(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Register(Reg),         // Ra
+    Immediate(Expr),       // 0x10 or label
+    Indirect(Reg),         // [Ra]
+    BaseOffset(Reg, Expr), // [Ra+0x10]
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{:#X}", value),
+            Expr::Symbol(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "{}", byte_to_register(register.0)),
+            Operand::Immediate(value) => write!(f, "{}", value),
+            Operand::Indirect(register) => write!(f, "[{}]", byte_to_register(register.0)),
+            Operand::BaseOffset(register, offset) => write!(f, "[{} + {}]", byte_to_register(register.0), offset),
+        }
+    }
+}
+
+/// How the two sides of a condition are compared, one for each of the IF
+/// instructions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,          // ==
+    NotEqual,       // !=
+    Less,           // <
+    Greater,        // >
+    LessOrEqual,    // <=
+    GreaterOrEqual, // >=
+}
+
+impl Comparison {
+    /// The instruction that jumps when the comparison holds, after a `CMP`.
+    pub fn jump(self) -> Instructions {
+        match self {
+            Comparison::Equal => Instructions::IF,
+            Comparison::NotEqual => Instructions::IFN,
+            Comparison::Less => Instructions::IFL,
+            Comparison::Greater => Instructions::IFG,
+            Comparison::LessOrEqual => Instructions::IFNE,
+            Comparison::GreaterOrEqual => Instructions::IFE,
+        }
+    }
+
+    /// The comparison that holds exactly when this one doesn't.
+    pub fn inverse(self) -> Comparison {
+        match self {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterOrEqual,
+            Comparison::Greater => Comparison::LessOrEqual,
+            Comparison::LessOrEqual => Comparison::Greater,
+            Comparison::GreaterOrEqual => Comparison::Less,
+        }
+    }
+}
+
+/// `Ra < 10`, as written after `.if` and `.while`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub left: Reg,
+    pub comparison: Comparison,
+    pub right: Operand,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub op_code: Instructions,
+    pub operands: Vec<Operand>,
+    pub span: Span,
+    pub expansion: Option<String>, // Pseudo-instruction this came from, see src/pseudo.rs
+}
+
+impl Instruction {
+    /// The last operand decides the mode, the ones before it are registers.
+    pub fn mode(&self) -> InstructionMode {
+        match self.operands.last() {
+            Some(Operand::Immediate(_)) => InstructionMode::Immediate,
+            Some(Operand::Indirect(_)) => InstructionMode::RegisterIndirect,
+            Some(Operand::BaseOffset(_, _)) => InstructionMode::BaseOffset,
+            Some(Operand::Register(_)) | None => InstructionMode::Register,
+        }
+    }
+
+    /// The value operand, if the mode has one.
+    pub fn value(&self) -> Option<&Expr> {
+        match self.operands.last() {
+            Some(Operand::Immediate(value)) | Some(Operand::BaseOffset(_, value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Number of words the instruction encodes to.
+    pub fn words(&self) -> u32 {
+        if self.value().is_some_and(Expr::is_wide) { 2 } else { 1 }
+    }
+}
+
+/// Written the way the disassembler shows it, `SET Ra, 0x10`.
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let operands: Vec<String> = self.operands.iter().map(Operand::to_string).collect();
+        write!(f, "{}", String::from(self.op_code))?;
+        if !operands.is_empty() {
+            write!(f, " {}", operands.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectiveKind {
+    Org(u32),                        // Start a section at a fixed address
+    Section(String),                 // Switch to a named section
+    Word(Vec<Expr>),                 // One word per value
+    Space(u32),                      // Zeroed bytes, a multiple of 4
+    DWord(Vec<u64>),                 // 64-bit values from `.dword` and `.double`, two words each
+    Entry(String),                   // Label the program starts at
+    Global(Vec<String>),             // Labels other objects can use
+    Extern(Vec<String>),             // Labels defined in other objects
+    Req(String, Reg),                // Another name for a register, from here on
+    Unreq(String),                   // Removes a name added with `.req`
+    Proc(String),                    // Starts a procedure, its labels are named `proc::label`
+    EndProc,                         // Ends the innermost procedure
+    If(Condition),                   // Runs the block if the condition holds, see src/control.rs
+    Else,                            // Runs the block if the `.if` condition didn't hold
+    EndIf,                           // Ends an `.if` block
+    While(Condition),                // Repeats the block while the condition holds
+    EndWhile,                        // Ends a `.while` block
+    Break,                           // Leaves the innermost `.while`
+    Continue,                        // Goes back to the condition of the innermost `.while`
+    Struct(String),                  // Starts a structure layout, its members become constants
+    Field(String, u32),              // `name .word` or `name .space` in a structure, size in words
+    EndStruct(String),               // `.ends`, holds the name of the structure's size constant
+    Enum(String),                    // Starts a list of constants numbered from 0
+    EnumMember(String, Option<u32>), // `NAME`, or `NAME = value` to set the value
+    EndEnum,                         // Ends an `.enum` block
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    pub kind: DirectiveKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    Label(Label),
+    Instruction(Instruction),
+    Directive(Directive),
+}
+
+/// An instruction lowered to the numbers that get encoded: register numbers and
+/// resolved values, in operand order.
+#[derive(Debug)]
+pub struct ASTNode {
+    pub op_code: Instructions,
+    pub mode: InstructionMode,
+    pub args: Vec<u32>,
+    pub section: usize,            // Section the instruction is in
+    pub address: u32,              // Address the instruction is placed at, relative to its section
+    pub extended: bool,            // Whether the value operand is stored in an extension word
+    pub label: Option<String>,     // Label the value operand refers to, if any
+    pub span: Span,                // Where the instruction was written
+    pub expansion: Option<String>, // Pseudo-instruction it was expanded from
+    pub token_repr: String,
+}
+
+impl ASTNode {
+    pub fn new(op_code: Instructions, mode: InstructionMode, args: Vec<u32>) -> Self {
+        let token_repr = String::from(op_code);
+        let extended = matches!(mode, InstructionMode::Immediate | InstructionMode::BaseOffset)
+            && args.last().is_some_and(|value| *value > 0xF);
+
+        Self {
+            op_code,
+            mode,
+            args,
+            section: 0,
+            address: 0,
+            extended,
+            label: None,
+            span: Span::default(),
+            expansion: None,
+            token_repr,
+        }
+    }
+}
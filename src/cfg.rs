@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use crate::ast::{DirectiveKind, Expr, Instruction, Operand, Statement};
+use crate::instructions::Instructions;
+
+// Control flow graph
+//
+// Splits the instructions of a file into basic blocks: a block starts at a label
+// (or `.proc`) and ends after JMP, an IF instruction, CALL, RET or HLT. Data
+// (`.word`, `.space`, `.dword`) and section changes also end a block, and the
+// code after them isn't reached by falling through.
+//
+// | Edge        | From                                  | To                        |
+// | Fallthrough | A block that doesn't end in JMP, RET  | The next block            |
+// |             | or HLT                                |                           |
+// | Taken       | A block ending in JMP or an IF        | The target label's block  |
+// | Call        | A block ending in CALL                | The target label's block  |
+// | Return      | A block ending in RET                 | The block after each CALL |
+// |             |                                       | that reaches it           |
+//
+// Only jumps to a label in the same file get an edge: a jump to an `.extern` or
+// through a register goes somewhere the graph doesn't know about. The graph is
+// built from the statements after label scoping and control flow lowering, so
+// `.if` and `.while` show up as the instructions they become.
+//
+// `cfg --dot` writes the graph for Graphviz (`dot -Tsvg`), one box per block
+// with its labels and instructions. Taken edges are solid, fallthrough dashed,
+// calls bold and returns dotted.
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub labels: Vec<String>,            // Labels at the start of the block
+    pub instructions: Vec<Instruction>, // Never empty
+    flows_on: bool,                     // Whether the next block follows it directly
+}
+
+impl BasicBlock {
+    /// The instruction that ends the block.
+    pub fn last(&self) -> &Instruction {
+        self.instructions.last().unwrap()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Taken,
+    Call,
+    Return,
+}
+
+impl EdgeKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Taken => "taken",
+            EdgeKind::Call => "call",
+            EdgeKind::Return => "return",
+        }
+    }
+
+    fn dot_style(self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "dashed",
+            EdgeKind::Taken => "solid",
+            EdgeKind::Call => "bold",
+            EdgeKind::Return => "dotted",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,        // In source order
+    pub edges: Vec<Edge>,
+    pub entry: Option<usize>,           // Block of `.entry`, otherwise the first block
+    pub labels: HashMap<String, usize>, // Block each label starts
+}
+
+/// Whether the instruction is one of the conditional jumps.
+pub fn is_branch(op_code: Instructions) -> bool {
+    matches!(op_code, Instructions::IF | Instructions::IFN | Instructions::IFG | Instructions::IFL | Instructions::IFE | Instructions::IFNE)
+}
+
+/// Whether the next instruction can't run after this one.
+fn ends_flow(op_code: Instructions) -> bool {
+    matches!(op_code, Instructions::JMP | Instructions::RET | Instructions::HLT)
+}
+
+fn ends_block(op_code: Instructions) -> bool {
+    ends_flow(op_code) || is_branch(op_code) || op_code == Instructions::CALL
+}
+
+/// The label a jump or call goes to, if it names one.
+pub fn target(instruction: &Instruction) -> Option<&str> {
+    match instruction.operands.first() {
+        Some(Operand::Immediate(Expr::Symbol(name))) => Some(name),
+        _ => None,
+    }
+}
+
+impl ControlFlowGraph {
+    /// Builds the graph of a file's statements, after label scoping and control
+    /// flow lowering.
+    pub fn build(statements: &[Statement]) -> Self {
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        let mut labels = HashMap::new();
+        let mut pending = Vec::new(); // Labels waiting for the next instruction
+        let mut entry = None;
+        let mut open = false;         // Whether the last block can take more instructions
+
+        for statement in statements {
+            match statement {
+                Statement::Label(label) => {
+                    pending.push(label.name.clone());
+                    open = false;
+                },
+                Statement::Directive(directive) => match &directive.kind {
+                    DirectiveKind::Proc(name) => {
+                        pending.push(name.clone());
+                        open = false;
+                    },
+                    DirectiveKind::Entry(name) => entry = Some(name.clone()),
+                    DirectiveKind::Org(_) | DirectiveKind::Section(_) | DirectiveKind::Word(_) | DirectiveKind::Space(_) | DirectiveKind::DWord(_) => {
+                        // Labels on data aren't code
+                        pending.clear();
+                        if let Some(block) = blocks.last_mut() {
+                            block.flows_on = false;
+                        }
+                        open = false;
+                    },
+                    _ => {},
+                },
+                Statement::Instruction(instruction) => {
+                    if !open {
+                        for label in &pending {
+                            labels.insert(label.clone(), blocks.len());
+                        }
+                        blocks.push(BasicBlock { labels: std::mem::take(&mut pending), instructions: Vec::new(), flows_on: true });
+                    }
+                    let block = blocks.last_mut().unwrap();
+                    block.instructions.push(instruction.clone());
+                    open = !ends_block(instruction.op_code);
+                },
+            }
+        }
+
+        if let Some(block) = blocks.last_mut() {
+            block.flows_on = false;
+        }
+
+        let mut edges = Vec::new();
+        for (index, block) in blocks.iter().enumerate() {
+            let last = block.last();
+            let target = target(last).and_then(|name| labels.get(name));
+            match last.op_code {
+                Instructions::JMP => {
+                    edges.extend(target.map(|to| Edge { from: index, to: *to, kind: EdgeKind::Taken }));
+                },
+                Instructions::CALL => {
+                    edges.extend(target.map(|to| Edge { from: index, to: *to, kind: EdgeKind::Call }));
+                },
+                op_code if is_branch(op_code) => {
+                    edges.extend(target.map(|to| Edge { from: index, to: *to, kind: EdgeKind::Taken }));
+                },
+                _ => {},
+            }
+            if block.flows_on && !ends_flow(last.op_code) {
+                edges.push(Edge { from: index, to: index + 1, kind: EdgeKind::Fallthrough });
+            }
+        }
+
+        let entry = match entry {
+            Some(name) => labels.get(&name).copied(),
+            None if blocks.is_empty() => None,
+            None => Some(0),
+        };
+
+        let mut graph = ControlFlowGraph { blocks, edges, entry, labels };
+        let calls: Vec<Edge> = graph.edges.iter().filter(|edge| edge.kind == EdgeKind::Call).copied().collect();
+        for call in calls {
+            let Some(after) = graph.fallthrough(call.from) else {
+                continue;
+            };
+            for ret in graph.returns_from(call.to) {
+                let edge = Edge { from: ret, to: after, kind: EdgeKind::Return };
+                if !graph.edges.contains(&edge) {
+                    graph.edges.push(edge);
+                }
+            }
+        }
+
+        graph
+    }
+
+    /// The name a block is shown with: its labels, or its number if it has none.
+    pub fn block_name(&self, block: usize) -> String {
+        match self.blocks[block].labels.as_slice() {
+            [] => format!("block {}", block),
+            labels => labels.join(", "),
+        }
+    }
+
+    /// The graph in Graphviz's DOT language.
+    pub fn to_dot(&self, name: &str) -> String {
+        let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+
+        let mut output = format!("digraph \"{}\" {{\n", escape(name));
+        output += "    node [shape=box, fontname=\"monospace\"];\n";
+        for (index, block) in self.blocks.iter().enumerate() {
+            // `\l` ends a left-aligned line
+            let mut label = format!("{}:\\l", escape(&self.block_name(index)));
+            for instruction in &block.instructions {
+                label += &format!("    {}\\l", escape(&instruction.to_string()));
+            }
+            let entry = if self.entry == Some(index) { ", penwidth=2" } else { "" };
+            output += &format!("    b{} [label=\"{}\"{}];\n", index, label, entry);
+        }
+        for edge in &self.edges {
+            output += &format!("    b{} -> b{} [label=\"{}\", style={}];\n", edge.from, edge.to, edge.kind.name(), edge.kind.dot_style());
+        }
+        output += "}\n";
+        output
+    }
+
+    /// The graph as text, each block followed by where it goes.
+    pub fn to_text(&self) -> String {
+        let mut output = String::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            let entry = if self.entry == Some(index) { " (entry)" } else { "" };
+            output += &format!("{}:{}\n", self.block_name(index), entry);
+            for instruction in &block.instructions {
+                output += &format!("    {}\n", instruction);
+            }
+            for edge in self.successors(index) {
+                output += &format!("    -> {} ({})\n", self.block_name(edge.to), edge.kind.name());
+            }
+            output += "\n";
+        }
+        output
+    }
+
+    /// Edges leaving the block.
+    pub fn successors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.from == block)
+    }
+
+    /// Edges coming into the block.
+    pub fn predecessors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.to == block)
+    }
+
+    /// The block that runs next if the block doesn't jump.
+    pub fn fallthrough(&self, block: usize) -> Option<usize> {
+        self.successors(block).find(|edge| edge.kind == EdgeKind::Fallthrough).map(|edge| edge.to)
+    }
+
+    /// Blocks ending in RET that a call to `target` can reach, following the
+    /// procedure's own jumps and stepping over the calls it makes.
+    pub fn returns_from(&self, target: usize) -> Vec<usize> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut stack = vec![target];
+        let mut returns = Vec::new();
+        while let Some(block) = stack.pop() {
+            if std::mem::replace(&mut seen[block], true) {
+                continue;
+            }
+            if self.blocks[block].last().op_code == Instructions::RET {
+                returns.push(block);
+            }
+            stack.extend(self.successors(block)
+                .filter(|edge| matches!(edge.kind, EdgeKind::Fallthrough | EdgeKind::Taken))
+                .map(|edge| edge.to));
+        }
+        returns.sort();
+        returns
+    }
+}
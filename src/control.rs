@@ -0,0 +1,119 @@
+use crate::ast::{Condition, DirectiveKind, Expr, Instruction, Label, Operand, Span, Statement};
+use crate::instructions::Instructions;
+
+// Structured control flow
+//
+// `.if` / `.else` / `.endif` and `.while` / `.endw` are lowered to `CMP`, the IF
+// instruction for the opposite comparison and `JMP`s between generated labels:
+//
+//   .if Ra == Rb           CMP Ra, Rb             .while Ra < 10      #while1.top:
+//       ...                IFN #if0.else              ...             CMP Ra, 10
+//   .else                  ...                    .endw               IFE #while1.end
+//       ...                JMP #if0.end                               ...
+//   .endif                 #if0.else:                                 JMP #while1.top
+//                          ...                                        #while1.end:
+//                          #if0.end:
+//
+// `.break` jumps to the end of the innermost `.while` and `.continue` back to its
+// condition. Conditions compare a register with a register or a value, using any
+// of ==, !=, <, >, <= and >=. This runs after label scoping, the generated labels
+// have a '#' so they can't clash with anything written in the source.
+
+enum Block {
+    If { number: usize, has_else: bool, span: Span },
+    While { number: usize, span: Span },
+}
+
+/// Replaces the control flow directives with instructions and labels.
+pub fn lower_control_flow(statements: &[Statement]) -> Vec<Statement> {
+    let mut output = Vec::new();
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut count = 0;
+
+    for statement in statements {
+        let directive = match statement {
+            Statement::Directive(directive) => directive,
+            _ => {
+                output.push(statement.clone());
+                continue;
+            },
+        };
+
+        let span = &directive.span;
+        let name = |text: String| Statement::Label(Label { name: text, span: span.clone() });
+        let instruction = |op_code: Instructions, operands: Vec<Operand>, from: &str| Statement::Instruction(Instruction {
+            op_code,
+            operands,
+            span: span.clone(),
+            expansion: Some(from.to_string()),
+        });
+        let jump = |target: String, from: &str| instruction(Instructions::JMP, vec![Operand::Immediate(Expr::Symbol(target))], from);
+        // Jumps to `target` when the condition doesn't hold
+        let skip_unless = |condition: &Condition, target: String, from: &str| vec![
+            instruction(Instructions::CMP, vec![Operand::Register(condition.left), condition.right.clone()], from),
+            instruction(condition.comparison.inverse().jump(), vec![Operand::Immediate(Expr::Symbol(target))], from),
+        ];
+        let innermost_while = |blocks: &[Block], directive: &str| blocks.iter().rev()
+            .find_map(|block| match block {
+                Block::While { number, .. } => Some(*number),
+                Block::If { .. } => None,
+            })
+            .unwrap_or_else(|| panic!("{}: {} outside of a .while", span, directive));
+
+        match &directive.kind {
+            DirectiveKind::If(condition) => {
+                output.extend(skip_unless(condition, format!("#if{}.else", count), ".if"));
+                blocks.push(Block::If { number: count, has_else: false, span: span.clone() });
+                count += 1;
+            },
+            DirectiveKind::Else => match blocks.last_mut() {
+                Some(Block::If { number, has_else, .. }) if !*has_else => {
+                    output.push(jump(format!("#if{}.end", number), ".else"));
+                    output.push(name(format!("#if{}.else", number)));
+                    *has_else = true;
+                },
+                Some(Block::If { .. }) => panic!("{}: .if already has an .else", span),
+                _ => panic!("{}: .else without an .if", span),
+            },
+            DirectiveKind::EndIf => match blocks.pop() {
+                Some(Block::If { number, has_else, .. }) => {
+                    if !has_else {
+                        output.push(name(format!("#if{}.else", number)));
+                    }
+                    output.push(name(format!("#if{}.end", number)));
+                },
+                _ => panic!("{}: .endif without an .if", span),
+            },
+            DirectiveKind::While(condition) => {
+                output.push(name(format!("#while{}.top", count)));
+                output.extend(skip_unless(condition, format!("#while{}.end", count), ".while"));
+                blocks.push(Block::While { number: count, span: span.clone() });
+                count += 1;
+            },
+            DirectiveKind::EndWhile => match blocks.pop() {
+                Some(Block::While { number, .. }) => {
+                    output.push(jump(format!("#while{}.top", number), ".endw"));
+                    output.push(name(format!("#while{}.end", number)));
+                },
+                _ => panic!("{}: .endw without a .while", span),
+            },
+            DirectiveKind::Break => {
+                let number = innermost_while(&blocks, ".break");
+                output.push(jump(format!("#while{}.end", number), ".break"));
+            },
+            DirectiveKind::Continue => {
+                let number = innermost_while(&blocks, ".continue");
+                output.push(jump(format!("#while{}.top", number), ".continue"));
+            },
+            _ => output.push(statement.clone()),
+        }
+    }
+
+    match blocks.last() {
+        Some(Block::If { span, .. }) => panic!("{}: .if is never closed with .endif", span),
+        Some(Block::While { span, .. }) => panic!("{}: .while is never closed with .endw", span),
+        None => {},
+    }
+
+    output
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+
+use crate::mapfile::LinkMap;
+use crate::utils::string_to_u32;
+
+// Debug information files
+//
+// Written with `--debug-info <file>` when assembling or linking, and read back by
+// `disasm --debug-info <file>` to show the source next to each address. It's a
+// line based text format, addresses are hex and in address units:
+//
+//   version 1
+//   file <index> <path>
+//   line <address> <file index> <line> <column>
+//   expansion <address> <name>
+//   label <address> <name>
+//   proc <address> <size> <name>
+//
+// There's a `line` record for the start of every instruction and data directive.
+// An instruction that a pseudo-instruction, `.if` or `.while` expanded to keeps
+// the position of what it was expanded from, and an `expansion` record after its
+// `line` record names it.
+
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugLine {
+    pub address: u32,
+    pub file: usize, // Index into `files`
+    pub line: u32,
+    pub column: u32,
+    pub expansion: Option<String>, // Pseudo-instruction or directive the instruction came from
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub lines: Vec<DebugLine>,               // Sorted by address
+    pub labels: Vec<(String, u32)>,          // Name, address
+    pub procedures: Vec<(String, u32, u32)>, // Name, address, size
+    sources: HashMap<usize, Vec<String>>,    // Lines of the source files that could be read
+}
+
+impl DebugInfo {
+    /// Collects the line table and labels of a linked program.
+    pub fn from_map(map: &LinkMap) -> Self {
+        let mut info = DebugInfo::default();
+
+        for line in &map.lines {
+            let file = match info.files.iter().position(|file| *file == line.file) {
+                Some(index) => index,
+                None => {
+                    info.files.push(line.file.clone());
+                    info.files.len() - 1
+                },
+            };
+            info.lines.push(DebugLine { address: line.address, file, line: line.line, column: line.column, expansion: line.expansion.clone() });
+        }
+
+        info.labels = map.symbols.iter().map(|symbol| (symbol.name.clone(), symbol.address)).collect();
+        info.procedures = map.symbols.iter()
+            .filter(|symbol| symbol.procedure)
+            .map(|symbol| (symbol.name.clone(), symbol.address, symbol.size))
+            .collect();
+        info
+    }
+
+    pub fn write(&self) -> String {
+        let mut output = format!("version {}\n", FORMAT_VERSION);
+        for (index, file) in self.files.iter().enumerate() {
+            output += &format!("file {} {}\n", index, file);
+        }
+        for line in &self.lines {
+            output += &format!("line 0x{:08X} {} {} {}\n", line.address, line.file, line.line, line.column);
+            if let Some(expansion) = &line.expansion {
+                output += &format!("expansion 0x{:08X} {}\n", line.address, expansion);
+            }
+        }
+        for (name, address) in &self.labels {
+            output += &format!("label 0x{:08X} {}\n", address, name);
+        }
+        for (name, address, size) in &self.procedures {
+            output += &format!("proc 0x{:08X} 0x{:X} {}\n", address, size, name);
+        }
+
+        output
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut info = DebugInfo::default();
+        let mut version = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |word: &str| string_to_u32(word).ok_or(format!("Line {}: invalid number {}", line_number, word));
+
+            match words.as_slice() {
+                [] => {},
+                ["version", value] => {
+                    let value = number(value)?;
+                    if value != FORMAT_VERSION {
+                        return Err(format!("Unsupported debug info version {}", value));
+                    }
+                    version = Some(value);
+                },
+                ["file", file_index, ..] => {
+                    if number(file_index)? as usize != info.files.len() {
+                        return Err(format!("Line {}: files must be numbered in order", line_number));
+                    }
+                    // Paths can contain spaces, so the path is the rest of the line
+                    let path = line.trim_start()["file".len()..].trim_start()[file_index.len()..].trim();
+                    info.files.push(path.to_string());
+                },
+                ["line", address, file, line, column] => {
+                    let file = number(file)? as usize;
+                    if file >= info.files.len() {
+                        return Err(format!("Line {}: unknown file {}", line_number, file));
+                    }
+                    info.lines.push(DebugLine { address: number(address)?, file, line: number(line)?, column: number(column)?, expansion: None });
+                },
+                ["expansion", address, name] => {
+                    let address = number(address)?;
+                    match info.lines.iter_mut().rev().find(|line| line.address == address) {
+                        Some(line) => line.expansion = Some(name.to_string()),
+                        None => return Err(format!("Line {}: expansion at 0x{:08X} has no line record", line_number, address)),
+                    }
+                },
+                ["label", address, name] => info.labels.push((name.to_string(), number(address)?)),
+                ["proc", address, size, name] => info.procedures.push((name.to_string(), number(address)?, number(size)?)),
+                _ => return Err(format!("Line {}: can't understand '{}'", line_number, line.trim())),
+            }
+        }
+
+        if version.is_none() {
+            return Err(String::from("Missing debug info version"));
+        }
+
+        info.lines.sort_by_key(|line| line.address);
+        Ok(info)
+    }
+
+    /// Reads the source files so their lines can be shown. Files that can't be
+    /// read are skipped, their positions are still shown.
+    pub fn load_sources(&mut self) {
+        for (index, file) in self.files.iter().enumerate() {
+            if let Ok(text) = read_to_string(file) {
+                self.sources.insert(index, text.lines().map(String::from).collect());
+            }
+        }
+    }
+
+    /// Returns the line table entry that starts exactly at the address.
+    pub fn line_at(&self, address: u32) -> Option<&DebugLine> {
+        self.lines.iter().find(|line| line.address == address)
+    }
+
+    /// Returns the labels defined at the address.
+    pub fn labels_at(&self, address: u32) -> impl Iterator<Item = &str> {
+        self.labels.iter().filter(move |(_, label_address)| *label_address == address).map(|(name, _)| name.as_str())
+    }
+
+    /// Returns the procedures that start at the address, outermost first.
+    pub fn procedures_at(&self, address: u32) -> Vec<&(String, u32, u32)> {
+        let mut procedures: Vec<_> = self.procedures.iter().filter(|(_, start, _)| *start == address).collect();
+        procedures.sort_by_key(|(_, _, size)| std::cmp::Reverse(*size));
+        procedures
+    }
+
+    /// Returns the procedures that end at the address, innermost first. Empty
+    /// procedures aren't included, they end where they start.
+    pub fn procedures_ending_at(&self, address: u32) -> Vec<&(String, u32, u32)> {
+        let mut procedures: Vec<_> = self.procedures.iter().filter(|(_, start, size)| *size > 0 && start + size == address).collect();
+        procedures.sort_by_key(|(_, start, _)| std::cmp::Reverse(*start));
+        procedures
+    }
+
+    /// Formats a line entry as `file:line:column`, followed by the source text if
+    /// it was loaded and what the instruction was expanded from.
+    pub fn describe(&self, line: &DebugLine) -> String {
+        let mut output = format!("{}:{}:{}", self.files[line.file], line.line, line.column);
+        let text = self.sources.get(&line.file).and_then(|source| source.get((line.line as usize).checked_sub(1)?));
+        if let Some(text) = text {
+            output += &format!("  {}", text.trim());
+        }
+        if let Some(expansion) = &line.expansion {
+            output += &format!(" (from {})", expansion);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use crate::control::lower_control_flow;
+    use crate::labels::scope_labels;
+    use crate::lexer::Lexer;
+    use crate::linker::link;
+    use crate::object::Object;
+    use crate::parser::parse;
+    use crate::program::{Addressing, Endian};
+
+    fn debug_info(source: &str) -> DebugInfo {
+        let statements = lower_control_flow(&scope_labels(&parse(&Lexer::new("prog.asm").tokenize(source)), false));
+        let object = Object::assemble(&Assembly::new("prog.asm", &statements, Addressing::Byte)).unwrap();
+        DebugInfo::from_map(&link(&[object], Endian::Big, None).unwrap().1)
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let info = debug_info(concat!(
+            ".proc main\n",
+            "    SET Ra, 1\n",
+            "    INC Ra\n",
+            "    RET\n",
+            ".endproc\n",
+            "table: .word 1, 2\n",
+        ));
+        let text = info.write();
+        assert!(text.starts_with("version 1\nfile 0 prog.asm\n"));
+
+        let read = DebugInfo::parse(&text).unwrap();
+        assert_eq!(read.files, info.files);
+        assert_eq!(read.lines, info.lines);
+        assert_eq!(read.labels, info.labels);
+        assert_eq!(read.procedures, info.procedures);
+        assert_eq!(read.write(), text);
+    }
+
+    #[test]
+    fn expansion_maps_to_its_source_line() {
+        let info = debug_info(concat!(
+            "main: SET Rb, 2\n",
+            "    PUSH Ra, Rb\n",
+            "    HLT\n",
+        ));
+        let info = DebugInfo::parse(&info.write()).unwrap();
+
+        // Every instruction PUSH became points back at line 2
+        let pushed: Vec<&DebugLine> = info.lines.iter().filter(|line| line.expansion.is_some()).collect();
+        assert!(pushed.len() > 1);
+        for line in &pushed {
+            assert_eq!((line.line, line.column, line.expansion.as_deref()), (2, 5, Some("PUSH")));
+        }
+        assert_eq!(info.describe(pushed[0]), "prog.asm:2:5 (from PUSH)");
+
+        let halt = info.lines.last().unwrap();
+        assert_eq!((halt.line, halt.expansion.as_deref()), (3, None));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        assert_eq!(DebugInfo::parse("version 2\n").unwrap_err(), "Unsupported debug info version 2");
+        assert_eq!(DebugInfo::parse("file 0 prog.asm\n").unwrap_err(), "Missing debug info version");
+        assert!(DebugInfo::parse("version 1\nexpansion 0x0 INC\n").is_err());
+    }
+}
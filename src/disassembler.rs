@@ -0,0 +1,94 @@
+use crate::debuginfo::DebugInfo;
+use crate::generator::decode_instruction;
+use crate::instructions::InstructionMode;
+use crate::ast::ASTNode;
+use crate::program::Program;
+use crate::utils::byte_to_register;
+
+/// Formats the operands of a decoded node the way they would be written in assembly.
+fn format_operands(node: &ASTNode) -> String {
+    let args = &node.args;
+    let mut operands: Vec<String> = Vec::new();
+
+    // Every mode starts with plain registers, only the tail differs
+    let register_count = match node.mode {
+        InstructionMode::Register => args.len(),
+        InstructionMode::Immediate | InstructionMode::RegisterIndirect => args.len().saturating_sub(1),
+        InstructionMode::BaseOffset => args.len().saturating_sub(2),
+    };
+
+    for arg in &args[..register_count] {
+        operands.push(byte_to_register(*arg));
+    }
+
+    let tail = &args[register_count..];
+    match (node.mode, tail) {
+        (InstructionMode::Immediate, [value]) => operands.push(format!("{:#X}", value)),
+        (InstructionMode::RegisterIndirect, [register]) => operands.push(format!("[{}]", byte_to_register(*register))),
+        (InstructionMode::BaseOffset, [register, offset]) => {
+            operands.push(format!("[{} + {:#X}]", byte_to_register(*register), offset))
+        },
+        // Malformed tails (eg: BaseOffset with a single operand) are shown as raw values
+        _ => operands.extend(tail.iter().map(|value| format!("{:#X}", value))),
+    }
+
+    operands.join(", ")
+}
+
+/// Disassembles every segment of the program. Words that don't decode are shown
+/// as `.word` so the listing still covers the whole image. With debug info,
+/// labels are shown above their address, source lines next to it and
+/// procedures are wrapped in `.proc` / `.endproc`.
+pub fn disassemble(program: &Program, debug: Option<&DebugInfo>) -> String {
+    let instruction_size = program.addressing.instruction_size();
+    let mut output = String::new();
+
+    for segment in &program.segments {
+        let mut index = 0;
+        while index < segment.words.len() {
+            let address = segment.origin + index as u32 * instruction_size;
+            let (text, used) = match decode_instruction(&segment.words[index..]) {
+                Some((node, used)) => {
+                    let operands = format_operands(&node);
+                    (format!("{} {}", node.token_repr, operands).trim_end().to_string(), used)
+                },
+                None => (format!(".word {:#010X}", segment.words[index]), 1),
+            };
+
+            let words: Vec<String> = segment.words[index..index + used].iter().map(|word| format!("{:08X}", word)).collect();
+            let line = format!("{:08X}:  {:<18} {}", address, words.join(" "), text);
+            match debug {
+                Some(debug) => {
+                    output += &procedure_ends(debug, address);
+                    for (name, _, size) in debug.procedures_at(address) {
+                        output += &format!("\n.proc {}\n", name);
+                        if *size == 0 {
+                            output += ".endproc\n";
+                        }
+                    }
+                    for label in debug.labels_at(address) {
+                        if !debug.procedures.iter().any(|(name, start, _)| name == label && *start == address) {
+                            output += &format!("{}:\n", label);
+                        }
+                    }
+                    match debug.line_at(address) {
+                        Some(source) => output += &format!("{:<48} ; {}\n", line, debug.describe(source)),
+                        None => output += &format!("{}\n", line),
+                    }
+                },
+                None => output += &format!("{}\n", line),
+            }
+            index += used;
+        }
+
+        if let Some(debug) = debug {
+            output += &procedure_ends(debug, segment.end(program.addressing));
+        }
+    }
+
+    output
+}
+
+fn procedure_ends(debug: &DebugInfo, address: u32) -> String {
+    debug.procedures_ending_at(address).iter().map(|_| ".endproc\n").collect()
+}
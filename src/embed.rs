@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use crate::program::Program;
+
+// Source arrays for embedding programs in host-side code
+//
+// The array holds the whole image from the lowest address, with gaps between
+// segments filled with zeros. The addresses of the labels the program exports
+// (every named label of a single file, only the `.global` ones when linking
+// several, see src/linker.rs) come alongside it as `{NAME}_LABEL_{LABEL}`, so
+// they can't clash with the array or its length.
+// Labels that only differ in characters an identifier can't hold (`main.loop`
+// and `main_loop`) would get the same constant, which is an error.
+
+const WORDS_PER_LINE: usize = 8;
+
+/// Turns an arbitrary name into a valid C / Rust identifier.
+pub fn identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    if identifier.is_empty() || identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        identifier.insert(0, '_');
+    }
+
+    identifier
+}
+
+/// The constant name of every label, checking no two are the same.
+fn label_constants(prefix: &str, program: &Program) -> Result<Vec<(String, u32)>, String> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    program.labels.iter().map(|(label, address)| {
+        let constant = format!("{}_LABEL_{}", prefix, identifier(label).to_uppercase());
+        if let Some(other) = seen.insert(constant.clone(), label) {
+            return Err(format!("Labels {} and {} would both be named {}", other, label, constant));
+        }
+        Ok((constant, *address))
+    }).collect()
+}
+
+fn array_lines(words: &[u32]) -> String {
+    let lines: Vec<String> = words
+        .chunks(WORDS_PER_LINE)
+        .map(|chunk| {
+            let words: Vec<String> = chunk.iter().map(|word| format!("0x{:08X}", word)).collect();
+            format!("    {},", words.join(", "))
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+/// Writes a C header with the program as a `uint32_t` array.
+pub fn write_c(program: &Program, name: &str) -> Result<String, String> {
+    let name = identifier(name);
+    let prefix = name.to_uppercase();
+    let words = program.image_words();
+
+    let mut output = String::new();
+    output += "// Generated by dbv_compiler, do not edit\n";
+    output += &format!("#ifndef {}_H\n", prefix);
+    output += &format!("#define {}_H\n\n", prefix);
+    output += "#include <stdint.h>\n\n";
+
+    output += &format!("#define {}_LEN {}\n", prefix, words.len());
+    output += &format!("#define {}_BASE 0x{:X}\n", prefix, program.start());
+    output += &format!("#define {}_ENTRY 0x{:X}\n\n", prefix, program.entry);
+
+    for (constant, address) in label_constants(&prefix, program)? {
+        output += &format!("#define {} 0x{:X}\n", constant, address);
+    }
+    if !program.labels.is_empty() {
+        output += "\n";
+    }
+
+    output += &format!("static const uint32_t {}[{}_LEN] = {{\n", name, prefix);
+    output += &array_lines(&words);
+    output += "\n};\n\n";
+    output += &format!("#endif // {}_H\n", prefix);
+
+    Ok(output)
+}
+
+/// Writes a Rust module with the program as a `[u32; N]` static.
+pub fn write_rust(program: &Program, name: &str) -> Result<String, String> {
+    let name = identifier(name).to_uppercase();
+    let words = program.image_words();
+
+    let mut output = String::new();
+    output += "// Generated by dbv_compiler, do not edit\n\n";
+
+    output += &format!("pub const {}_LEN: usize = {};\n", name, words.len());
+    output += &format!("pub const {}_BASE: u32 = 0x{:X};\n", name, program.start());
+    output += &format!("pub const {}_ENTRY: u32 = 0x{:X};\n\n", name, program.entry);
+
+    for (constant, address) in label_constants(&name, program)? {
+        output += &format!("pub const {}: u32 = 0x{:X};\n", constant, address);
+    }
+    if !program.labels.is_empty() {
+        output += "\n";
+    }
+
+    output += &format!("pub static {}: [u32; {}_LEN] = [\n", name, name);
+    output += &array_lines(&words);
+    output += "\n];\n";
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use crate::control::lower_control_flow;
+    use crate::labels::scope_labels;
+    use crate::lexer::Lexer;
+    use crate::linker::link;
+    use crate::object::Object;
+    use crate::parser::parse;
+    use crate::program::{Addressing, Endian, Segment};
+
+    fn program(labels: &[(&str, u32)]) -> Program {
+        let mut program = Program::new(vec![Segment::new(0, vec![0; 2])], 0, Endian::Big, Addressing::Byte);
+        program.labels = labels.iter().map(|(label, address)| (label.to_string(), *address)).collect();
+        program
+    }
+
+    fn linked(files: &[(&str, &str)]) -> Program {
+        let objects: Vec<Object> = files.iter().map(|(file, source)| {
+            let statements = lower_control_flow(&scope_labels(&parse(&Lexer::new(file).tokenize(source)), false));
+            Object::assemble(&Assembly::new(file, &statements, Addressing::Byte)).unwrap()
+        }).collect();
+        link(&objects, Endian::Big, None).unwrap().0
+    }
+
+    const MAIN: &str = concat!(
+        ".global main\n",
+        ".extern helper\n",
+        "main: .if Ra == 0\n",
+        "    CALL helper\n",
+        ".endif\n",
+        "loop: JMP .again\n",
+        ".again: JMP +\n",
+        "+:  HLT\n",
+    );
+    const HELPER: &str = concat!(
+        ".global helper\n",
+        "helper: .if Ra == 1\n",
+        "    INC Ra\n",
+        ".endif\n",
+        "loop: JMP .again\n",
+        ".again: JMP +\n",
+        "+:  RET\n",
+    );
+
+    #[test]
+    fn linked_objects_export_their_globals() {
+        let program = linked(&[("main.asm", MAIN), ("helper.asm", HELPER)]);
+
+        // Both objects have a `loop`, `loop.again`, `#if0.else` and anonymous label
+        let c = write_c(&program, "prog").unwrap();
+        let defines: Vec<&str> = c.lines().filter(|line| line.starts_with("#define PROG_LABEL_")).collect();
+        assert_eq!(defines, vec!["#define PROG_LABEL_MAIN 0x0", "#define PROG_LABEL_HELPER 0x28"]);
+        assert!(write_rust(&program, "prog").is_ok());
+    }
+
+    #[test]
+    fn single_file_exports_its_own_labels() {
+        let program = linked(&[("main.asm", MAIN.replace(".extern helper\n", "").replace("CALL helper", "NOP").as_str())]);
+
+        let rust = write_rust(&program, "prog").unwrap();
+        let constants: Vec<&str> = rust.lines().filter(|line| line.starts_with("pub const PROG_LABEL_")).collect();
+        assert_eq!(constants, vec![
+            "pub const PROG_LABEL_MAIN: u32 = 0x0;",
+            "pub const PROG_LABEL_LOOP: u32 = 0x10;",
+            "pub const PROG_LABEL_LOOP_AGAIN: u32 = 0x18;",
+        ]);
+    }
+
+    #[test]
+    fn labels_get_their_own_names() {
+        let program = program(&[("main", 0x0), ("len", 0x4)]);
+
+        let rust = write_rust(&program, "main").unwrap();
+        assert!(rust.contains("pub const MAIN_LABEL_MAIN: u32 = 0x0;"));
+        assert!(rust.contains("pub static MAIN: [u32; MAIN_LEN]"));
+
+        let c = write_c(&program, "main").unwrap();
+        assert!(c.contains("#define MAIN_LABEL_LEN 0x4"));
+        assert_eq!(c.matches("#define MAIN_LEN ").count(), 1);
+    }
+
+    #[test]
+    fn labels_with_the_same_constant_are_rejected() {
+        let program = program(&[("main.loop", 0x0), ("main_loop", 0x4)]);
+        assert!(write_c(&program, "program").is_err());
+        assert!(write_rust(&program, "program").is_err());
+    }
+}
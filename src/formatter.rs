@@ -0,0 +1,215 @@
+use crate::lexer::Lexer;
+use crate::pseudo::is_pseudo;
+use crate::registers::register_number;
+use crate::token::{Token, TokenKind};
+use crate::instructions::Instructions;
+
+// Source formatting
+//
+// `fmt` rewrites a file into one style:
+//
+//   ; Comments on their own line stay in column 0, or are indented with the code
+//   loop:
+//       ADD     Ra, Ra, 1       ; Labels get their own line in column 0
+//       CMP     Ra, 10          ; Instructions and directives are indented and
+//       IFL     loop            ; their operands start in the same column
+//       .word   1, 2, 3
+//
+// Mnemonics are upper case, directives lower case and registers are written
+// `Ra`, `R0` or `SP`. A comma is followed by one space, comparisons and the `+`
+// in `[Rb + 4]` have one on each side, other tokens keep whether they had space
+// between them. Comments after code are lined up with
+// the others in the same run of lines, a blank line or a comment on its own
+// ends the run. Everything else, including comment text, numbers and strings,
+// is kept as written, so formatting a formatted file changes nothing.
+
+const INDENT: usize = 4;
+const MNEMONIC_WIDTH: usize = 8;
+
+enum Line {
+    Blank,
+    Comment { indented: bool, text: String },
+    Code { code: String, comment: Option<String> },
+}
+
+/// Formats a whole file. Panics like the assembler if the file doesn't lex.
+pub fn format(file: &str, input: &str) -> String {
+    let tokens = Lexer::new(file).tokenize(input);
+    let source: Vec<Vec<char>> = input.split('\n').map(|line| line.trim_end_matches('\r').chars().collect()).collect();
+    let text = |token: &Token| -> String {
+        let start = token.span.column - 1;
+        source[token.span.line - 1][start..start + token.span.length].iter().collect()
+    };
+
+    let mut lines = Vec::new();
+    for line in tokens.split(|token| token.kind == TokenKind::Newline) {
+        let (mut code, comment) = match line.split_last() {
+            Some((Token { kind: TokenKind::Comment(comment), .. }, code)) => (code, Some(format!(";{}", comment))),
+            _ => (line, None),
+        };
+
+        if code.is_empty() {
+            lines.push(match comment {
+                Some(text) => Line::Comment { indented: line[0].span.column > 1, text },
+                None => Line::Blank,
+            });
+            continue;
+        }
+
+        if code.len() >= 2 && code[1].kind == TokenKind::Punctuation(':') {
+            let label = format!("{}:", text(&code[0]));
+            code = &code[2..];
+            if code.is_empty() {
+                lines.push(Line::Code { code: label, comment });
+                continue;
+            }
+            lines.push(Line::Code { code: label, comment: None });
+        }
+
+        lines.push(Line::Code { code: format_statement(code, &text), comment });
+    }
+
+    // The lexer ends every line with a newline, even the last one
+    while matches!(lines.last(), Some(Line::Blank)) {
+        lines.pop();
+    }
+
+    let mut output = String::new();
+    for run in lines.chunk_by(|a, b| matches!((a, b), (Line::Code { .. }, Line::Code { .. }))) {
+        let column = run.iter()
+            .filter_map(|line| match line {
+                Line::Code { code, comment: Some(_) } => Some(code.chars().count() + 2),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        for line in run {
+            match line {
+                Line::Blank => {},
+                Line::Comment { indented, text } => {
+                    output.push_str(&" ".repeat(if *indented { INDENT } else { 0 }));
+                    output.push_str(text);
+                },
+                Line::Code { code, comment } => {
+                    output.push_str(code);
+                    if let Some(comment) = comment {
+                        output.push_str(&" ".repeat(column - code.chars().count()));
+                        output.push_str(comment);
+                    }
+                },
+            }
+            output.push('\n');
+        }
+    }
+
+    output
+}
+
+/// An indented instruction or directive, with its operands in their column.
+fn format_statement(tokens: &[Token], text: &impl Fn(&Token) -> String) -> String {
+    let head = match &tokens[0].kind {
+        TokenKind::Identifier(name) if name.starts_with('.') => name.to_lowercase(),
+        TokenKind::Identifier(name) if Instructions::try_from(name.clone()).is_ok() || is_pseudo(name) => name.to_uppercase(),
+        _ => operand_text(&tokens[0], text),
+    };
+
+    let mut line = format!("{}{}", " ".repeat(INDENT), head);
+    let Some(first) = tokens.get(1) else {
+        return line;
+    };
+    line.push_str(&" ".repeat(MNEMONIC_WIDTH.saturating_sub(head.chars().count()).max(1)));
+    line.push_str(&operand_text(first, text));
+
+    // Runs of `= ! < >` are one operator, like `==` or `<=`
+    let is_operator = |token: &Token| matches!(token.kind, TokenKind::Punctuation('=' | '!' | '<' | '>'));
+    let is_plus = |token: &Token| token.kind == TokenKind::Punctuation('+');
+    let mut depth = 0; // Brackets open before `token`
+    for pair in tokens[1..].windows(2) {
+        let (before, token) = (&pair[0], &pair[1]);
+        match before.kind {
+            TokenKind::Punctuation('[') => depth += 1,
+            TokenKind::Punctuation(']') => depth -= 1,
+            _ => {},
+        }
+
+        let spaced = token.span.column > before.span.column + before.span.length;
+        let space = match (&before.kind, &token.kind) {
+            (_, TokenKind::Punctuation(',')) => false,
+            (TokenKind::Punctuation(','), _) => true,
+            _ if is_operator(before) && is_operator(token) => false,
+            _ if is_operator(before) || is_operator(token) => true,
+            (TokenKind::Punctuation('[' | '('), _) | (_, TokenKind::Punctuation(']' | ')')) => false,
+            // `[Rb + 4]`, a `+` or `-` anywhere else is part of a value or label
+            _ if depth > 0 && (is_plus(before) || is_plus(token)) => true,
+            _ => spaced,
+        };
+        if space {
+            line.push(' ');
+        }
+        line.push_str(&operand_text(token, text));
+    }
+
+    line
+}
+
+/// The token as written, or a register in its usual spelling.
+fn operand_text(token: &Token, text: &impl Fn(&Token) -> String) -> String {
+    match &token.kind {
+        TokenKind::Identifier(name) if register_number(name).is_some() => {
+            let upper = name.to_uppercase();
+            // `Ra` - `Rp` have a lower case letter, `R0` - `R15` and ABI names are all upper case
+            match upper.strip_prefix('R') {
+                Some(letter) if letter.len() == 1 && letter.chars().all(|c| c.is_ascii_alphabetic()) => format!("R{}", letter.to_lowercase()),
+                _ => upper,
+            }
+        },
+        _ => text(token),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CRLF line endings, tabs, labels with statements and blocks
+    const MESSY: &str = concat!(
+        "; Header\r\n",
+        "start: set ra ,  r1\t;  keep   THIS ;x\r\n",
+        "\t ld rB,[ sp+4 ]\r\n",
+        "   ; indented note\r\n",
+        ".Struct Point\r\n",
+        "\tx .word\r\n",
+        "  y   .word 2 ; second\r\n",
+        ".ends\r\n",
+        "loop: .if Ra==Rb\r\n",
+        "    LD Ra, [Rc+ 4]\r\n",
+        "  .endif\r\n",
+        "  .while ra < = 10 ; loop\r\n",
+        "  inc RA\r\n",
+        ".endw\r\n",
+        "\r\n",
+        "\r\n",
+    );
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let once = format("test.asm", MESSY);
+        let twice = format("test.asm", &once);
+        assert_eq!(once, twice);
+        assert!(!once.contains('\r') && !once.contains('\t'));
+        assert!(once.ends_with(".endw\n"));
+    }
+
+    #[test]
+    fn spacing_is_normalized() {
+        let formatted = format("test.asm", MESSY);
+        assert!(formatted.contains("start:\n    SET     Ra, R1  ;  keep   THIS ;x\n"));
+        assert!(formatted.contains("\n    ; indented note\n"));
+        assert!(formatted.contains("    LD      Rb, [SP + 4]\n"));
+        assert!(formatted.contains("    .if     Ra == Rb\n"));
+        assert!(formatted.contains("    LD      Ra, [Rc + 4]\n"));
+        assert!(formatted.contains("    .while  Ra <= 10  ; loop\n"));
+        assert_eq!(format("test.asm", "JMP --\n.word -1, +\n"), "    JMP     --\n    .word   -1, +\n");
+    }
+}
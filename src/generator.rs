@@ -1,95 +1,216 @@
 use crate::instructions::{Instructions, InstructionMode};
 
-use super::parser::ASTNode;
+use super::assembler::Assembly;
+use super::ast::ASTNode;
+
+// Encoding:
+//
+// | 31..24 | 23..22 | 21 | 20..18 | 17..16   | 15..12 | 11..8 | 7..4  | 3..0  |
+// | Opcode |  Mode  | E  | Count  | Reserved | Slot0  | Slot1 | Slot2 | Slot3 |
+//
+// Count is the number of operands (0 - 4), and each operand gets a nibble slot
+// in order. In Immediate and BaseOffset mode the last operand is a value: if it
+// fits in a nibble it is stored in its slot, otherwise E is set, its slot is left
+// as 0 and the value is stored in the next word (the extension word). Label
+// operands always use the extension word, whatever their value.
+//
+// A decoder only needs to look at E to know whether the next word belongs to
+// this instruction.
+
+/// Bumped whenever the encoding or the opcode numbering changes.
+pub const ISA_VERSION: u16 = 2;
+
+pub const OPCODE_SHIFT: u32 = 24;
+pub const MODE_SHIFT: u32 = 22;
+pub const EXTENSION_FLAG: u32 = 1 << 21;
+pub const COUNT_SHIFT: u32 = 18;
+pub const MAX_OPERANDS: usize = 4;
 
 pub struct CodeGenerator;
 
 impl CodeGenerator {
-    pub fn generate(nodes: Vec<ASTNode>) -> Vec<u32> {
-        let nodes: Vec<u32> = nodes.iter().flat_map(|node| {
-            let bytes = encode_instruction(node.op_code, node.mode, &node.args);
-            // If extension is equal to 0, remove it
-            if bytes[1] == 0 {
-                vec![bytes[0]]
-            }else{
-                bytes
-            }
-        }).collect();
+    /// Encodes the assembly's instructions and data into the words of each of
+    /// its sections.
+    pub fn generate(assembly: &Assembly) -> Vec<Vec<u32>> {
+        let instruction_size = assembly.addressing.instruction_size();
+        let mut sections: Vec<Vec<u32>> = assembly.sections.iter()
+            .map(|section| vec![0; (section.size / instruction_size) as usize])
+            .collect();
+
+        for node in &assembly.nodes {
+            let words = encode_instruction(node.op_code, node.mode, &node.args, node.extended);
+            let expansion = node.expansion.as_ref().map_or(String::new(), |pseudo| format!(" ; from {}", pseudo));
+            println!("{}+{:08X}: {:<8} {:08X?}{}", assembly.sections[node.section].name, node.address, node.token_repr, words, expansion);
+
+            let index = (node.address / instruction_size) as usize;
+            sections[node.section][index..index + words.len()].copy_from_slice(&words);
+        }
 
-        for node in nodes.iter() {
-            println!("{0:8X}", node);
+        for (section, address, _, words) in &assembly.data {
+            let index = (address / instruction_size) as usize;
+            sections[*section][index..index + words.len()].copy_from_slice(words);
         }
 
-        nodes
+        sections
     }
 }
 
-pub fn encode_instruction(instruction: Instructions, mode: InstructionMode, arguments: &[u32]) -> Vec<u32>{
-    let mut raw_instruction: u32 = 0;
-    let mut extension = 0;
+fn mode_to_bits(mode: InstructionMode) -> u32 {
+    match mode {
+        InstructionMode::Register => 0b00,
+        InstructionMode::Immediate => 0b01,
+        InstructionMode::RegisterIndirect => 0b10,
+        InstructionMode::BaseOffset => 0b11,
+    }
+}
+
+fn bits_to_mode(bits: u32) -> InstructionMode {
+    match bits & 0b11 {
+        0b00 => InstructionMode::Register,
+        0b01 => InstructionMode::Immediate,
+        0b10 => InstructionMode::RegisterIndirect,
+        _ => InstructionMode::BaseOffset,
+    }
+}
 
+/// Returns true if the last operand of an instruction in this mode is a value
+/// that may need an extension word.
+fn has_value_operand(mode: InstructionMode) -> bool {
+    matches!(mode, InstructionMode::Immediate | InstructionMode::BaseOffset)
+}
+
+/// Encodes a single instruction. Returns one word, or two if the value operand
+/// needed an extension word. `extended` forces the extension word even if the
+/// value would fit in a nibble.
+pub fn encode_instruction(instruction: Instructions, mode: InstructionMode, arguments: &[u32], extended: bool) -> Vec<u32>{
     let args_len = arguments.len();
+    if args_len > MAX_OPERANDS {
+        panic!("Too many operands for {:?}: {}", instruction, args_len);
+    }
+
+    let mut raw_instruction: u32 = 0;
+    raw_instruction |= (instruction as u32) << OPCODE_SHIFT;
+    raw_instruction |= mode_to_bits(mode) << MODE_SHIFT;
+    raw_instruction |= (args_len as u32) << COUNT_SHIFT;
+
+    let mut extension = None;
+    for (i, arg) in arguments.iter().enumerate() {
+        let shift = 4 * (3 - i as u32);
+        if has_value_operand(mode) && i == args_len - 1 && (extended || *arg > 0xF) {
+            raw_instruction |= EXTENSION_FLAG;
+            extension = Some(*arg);
+        } else {
+            raw_instruction |= (arg & 0xF) << shift;
+        }
+    }
 
-    // Format:
-    // | 0000_0000 | 0000 | 0000_0000_0000_0000_0000 | -> 32 bits
-    // |  Opcode   | Mode |         Arguments        |
-
-    // Encode the opcode
-    raw_instruction |= (instruction as u32) << 24; // 0b1111_1111_0000_0000_0000_0000_0000_0000
-
-    // Encode the mode
-    match mode{
-        InstructionMode::Register => {
-            raw_instruction |= 0x00000000; // 0b0000_0000_0000_0000_0000_0000_0000_0000
-        },
-        InstructionMode::Immediate => {
-            raw_instruction |= 0x400000; // 0b0000_0000_0100_0000_0000_0000_0000_0000
-        },
-        InstructionMode::RegisterIndirect => {
-            raw_instruction |= 0x800000; // 0b0000_0000_1000_0000_0000_0000_0000_0000
-        },
-        InstructionMode::BaseOffset => {
-            raw_instruction |= 0xC00000; // 0b0000_0000_1100_0000_0000_0000_0000_0000
-        },
+    match extension {
+        Some(extension) => vec![raw_instruction, extension],
+        None => vec![raw_instruction],
     }
+}
 
-    // Encode the arguments
-    match mode{
-        InstructionMode::Register => {
-            // As a loop (up to 4 arguments)
-            for (i, arg) in arguments.iter().enumerate() {
-                raw_instruction |= (arg & 0xF) << (4 * (3 - i));
-            }
-        },
-        InstructionMode::Immediate => {
-             for (i, arg) in arguments.iter().enumerate() {
-                if i == args_len - 1 {
-                    // If the argument is greater than 0xF, we need to set the extension bit
-                    if arg > &0xF {
-                        println!("Extended: {:#X} for {:#X}", arg, instruction as u32);
-                        raw_instruction |= 0x1; // 0b0000_0000_0000_0000_0000_0000_0000_0001
-                        extension = *arg;
-                    }else{
-                        raw_instruction |= (arg & 0xF) << 4; // We fit in a nibble so we can just set the bits
+/// Decodes the instruction at the start of `words`. Returns the node and the
+/// number of words it used, or None if the words don't form a valid instruction.
+pub fn decode_instruction(words: &[u32]) -> Option<(ASTNode, usize)> {
+    let raw_instruction = *words.first()?;
+
+    let op_code = Instructions::from_u8((raw_instruction >> OPCODE_SHIFT) as u8)?;
+    let mode = bits_to_mode(raw_instruction >> MODE_SHIFT);
+    let args_len = ((raw_instruction >> COUNT_SHIFT) & 0b111) as usize;
+    if args_len > MAX_OPERANDS {
+        return None;
+    }
+
+    let extended = raw_instruction & EXTENSION_FLAG != 0;
+    if extended && (!has_value_operand(mode) || args_len == 0) {
+        return None;
+    }
+
+    let mut args: Vec<u32> = (0..args_len)
+        .map(|i| (raw_instruction >> (4 * (3 - i as u32))) & 0xF)
+        .collect();
+
+    let mut used = 1;
+    if extended {
+        args[args_len - 1] = *words.get(1)?;
+        used += 1;
+    }
+
+    let mut node = ASTNode::new(op_code, mode, args);
+    node.extended = extended;
+
+    Some((node, used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [InstructionMode; 4] = [
+        InstructionMode::Register,
+        InstructionMode::Immediate,
+        InstructionMode::RegisterIndirect,
+        InstructionMode::BaseOffset,
+    ];
+
+    fn operand_sets(mode: InstructionMode) -> Vec<Vec<u32>> {
+        let registers = [0x0, 0x1, 0x7, 0xF];
+        let values = [0x0, 0x1, 0xF, 0x10, 0x4000, 0xAAAA, 0xFFFF_FFFF];
+
+        let mut sets = vec![Vec::new()];
+        for len in 1..=MAX_OPERANDS {
+            for &register in &registers {
+                let mut args = vec![register; len];
+                if has_value_operand(mode) {
+                    for &value in &values {
+                        args[len - 1] = value;
+                        sets.push(args.clone());
                     }
-                }else{
-                    raw_instruction |= (arg & 0xF) << (4 * (3 - i));
+                } else {
+                    sets.push(args);
                 }
             }
-        },
-        InstructionMode::RegisterIndirect => {
-            // As a loop (up to 4 arguments)
-            for (i, arg) in arguments.iter().enumerate() {
-                raw_instruction |= (arg & 0xF) << (4 * (3 - i));
-            }
-        },
-        InstructionMode::BaseOffset => {
-            // As a loop (up to 4 arguments)
-            for (i, arg) in arguments.iter().enumerate() {
-                raw_instruction |= (arg & 0xF) << (4 * (3 - i));
+        }
+        sets
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let mut op = 0;
+        while let Some(op_code) = Instructions::from_u8(op) {
+            for mode in MODES {
+                for args in operand_sets(mode) {
+                    for extended in [false, true] {
+                        if extended && (!has_value_operand(mode) || args.is_empty()) {
+                            continue;
+                        }
+
+                        let words = encode_instruction(op_code, mode, &args, extended);
+                        let (node, used) = decode_instruction(&words)
+                            .unwrap_or_else(|| panic!("Failed to decode {:?} {:?} {:?}", op_code, mode, args));
+
+                        assert_eq!(used, words.len());
+                        assert_eq!(node.op_code, op_code);
+                        assert_eq!(node.mode, mode);
+                        assert_eq!(node.args, args);
+                        assert_eq!(node.extended, words.len() == 2);
+                    }
+                }
             }
-        },
+            op += 1;
+        }
     }
 
-    vec![raw_instruction, extension]
-}
\ No newline at end of file
+    #[test]
+    fn odd_last_register_is_not_an_extension() {
+        // Slot 3 used to double as the extension flag
+        let words = encode_instruction(Instructions::ADD, InstructionMode::Register, &[0x0, 0x1, 0x2, 0x1], false);
+        assert_eq!(words.len(), 1);
+        assert_eq!(words[0] & EXTENSION_FLAG, 0);
+
+        let (node, used) = decode_instruction(&[words[0], 0xDEAD_BEEF]).unwrap();
+        assert_eq!(used, 1);
+        assert_eq!(node.args, vec![0x0, 0x1, 0x2, 0x1]);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::program::{Endian, Addressing, Program, Segment};
+use crate::utils::parse_hex;
+
+// Intel HEX
+//
+// Every line is a record:
+// | : | Length | Address | Type | Data          | Checksum |
+// | : |   LL   |  AAAA   |  TT  | DD DD DD ...  |    CC    |
+//
+// The checksum is the two's complement of the sum of every byte in the record.
+// Record addresses are in the program's address units, so with word addressing
+// each address holds a 16-bit word.
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+const BYTES_PER_RECORD: usize = 16;
+
+fn record(record_type: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend(data);
+
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+    bytes.push(checksum);
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(":{}\n", hex)
+}
+
+/// Writes the program as Intel HEX. Only the segments are written, so gaps left
+/// by `.org` stay empty instead of being filled with zeros.
+pub fn write(program: &Program) -> String {
+    let unit = program.addressing.unit_size();
+    let mut output = String::new();
+    let mut upper_address = 0;
+
+    let mut segments: Vec<&Segment> = program.segments.iter().collect();
+    segments.sort_by_key(|segment| segment.origin);
+
+    for segment in segments {
+        let bytes = program.segment_bytes(segment);
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let address = segment.origin + offset as u32 / unit;
+            if address >> 16 != upper_address {
+                upper_address = address >> 16;
+                output += &record(EXTENDED_LINEAR_ADDRESS, 0, &(upper_address as u16).to_be_bytes());
+            }
+
+            // A record can't cross into the next 64K block
+            let to_boundary = ((0x10000 - (address & 0xFFFF)) * unit) as usize;
+            let length = BYTES_PER_RECORD.min(bytes.len() - offset).min(to_boundary);
+
+            output += &record(DATA, address as u16, &bytes[offset..offset + length]);
+            offset += length;
+        }
+    }
+
+    output += &record(START_LINEAR_ADDRESS, 0, &program.entry.to_be_bytes());
+    output += &record(END_OF_FILE, 0, &[]);
+
+    output
+}
+
+/// Reads an Intel HEX file back into a program.
+pub fn read(text: &str, endian: Endian, addressing: Addressing) -> Result<Program, String> {
+    let unit = addressing.unit_size();
+    let mut chunks: Vec<(u32, Vec<u8>)> = Vec::new(); // Address, bytes
+    let mut upper_address = 0;
+    let mut entry = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let hex = match line.strip_prefix(':') {
+            Some(hex) => hex,
+            None => return Err(format!("Line {}: record doesn't start with ':'", line_number)),
+        };
+
+        let bytes = parse_hex(hex).map_err(|err| format!("Line {}: {}", line_number, err))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(format!("Line {}: record length doesn't match its contents", line_number));
+        }
+
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if sum != 0 {
+            return Err(format!("Line {}: checksum mismatch", line_number));
+        }
+
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let record_type = bytes[3];
+        let data = &bytes[4..bytes.len() - 1];
+
+        match record_type {
+            DATA => {
+                if !(data.len() as u32).is_multiple_of(unit) {
+                    return Err(format!("Line {}: record doesn't hold whole address units", line_number));
+                }
+
+                let address = upper_address + address;
+                match chunks.last_mut() {
+                    Some((start, bytes)) if *start + bytes.len() as u32 / unit == address => bytes.extend(data),
+                    _ => chunks.push((address, data.to_vec())),
+                }
+            },
+            END_OF_FILE => break,
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                upper_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            },
+            START_SEGMENT_ADDRESS if data.len() == 4 => {
+                let segment = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let offset = u16::from_be_bytes([data[2], data[3]]) as u32;
+                entry = Some((segment << 4) + offset);
+            },
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                upper_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            },
+            START_LINEAR_ADDRESS if data.len() == 4 => {
+                entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+            },
+            _ => return Err(format!("Line {}: invalid record type {:02X}", line_number, record_type)),
+        }
+    }
+
+    Program::from_chunks(chunks, entry, endian, addressing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(addressing: Addressing) -> Program {
+        let segments = vec![
+            Segment::new(0x100, vec![0x1122_3344, 0x5566_7788, 0x99AA_BBCC]),
+            // Crosses into the next 64K block
+            Segment::new(0x1FFF8, vec![0x0000_0001, 0x0000_0002, 0x0000_0003, 0x0000_0004]),
+        ];
+        Program::new(segments, 0x104, Endian::Big, addressing)
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        for addressing in [Addressing::Byte, Addressing::Word] {
+            for endian in [Endian::Big, Endian::Little] {
+                let mut original = program(addressing);
+                original.endian = endian;
+
+                let read = read(&write(&original), endian, addressing).unwrap();
+                assert_eq!(read.segments, original.segments);
+                assert_eq!(read.entry, original.entry);
+            }
+        }
+    }
+
+    #[test]
+    fn records_split_at_64k_boundary() {
+        let text = write(&program(Addressing::Byte));
+        let lines: Vec<&str> = text.lines().collect();
+
+        // 8 bytes up to 0x1FFFF, then the upper address moves to 2 for the rest
+        let crossing = lines.iter().position(|line| line.starts_with(":08FFF800")).unwrap();
+        assert_eq!(lines[crossing - 1], record(EXTENDED_LINEAR_ADDRESS, 0, &[0x00, 0x01]).trim_end());
+        assert_eq!(lines[crossing + 1], record(EXTENDED_LINEAR_ADDRESS, 0, &[0x00, 0x02]).trim_end());
+        assert!(lines[crossing + 2].starts_with(":08000000"));
+    }
+
+    #[test]
+    fn extended_segment_address() {
+        let text = record(EXTENDED_SEGMENT_ADDRESS, 0, &[0x10, 0x00])
+            + &record(DATA, 0x0010, &[0xDE, 0xAD, 0xBE, 0xEF])
+            + &record(START_SEGMENT_ADDRESS, 0, &[0x10, 0x00, 0x00, 0x10])
+            + &record(END_OF_FILE, 0, &[]);
+
+        let program = read(&text, Endian::Big, Addressing::Byte).unwrap();
+        assert_eq!(program.segments, vec![Segment::new(0x10010, vec![0xDEAD_BEEF])]);
+        assert_eq!(program.entry, 0x10010);
+    }
+
+    #[test]
+    fn extended_linear_address() {
+        let text = record(EXTENDED_LINEAR_ADDRESS, 0, &[0x00, 0x03])
+            + &record(DATA, 0x0020, &[0x01, 0x02, 0x03, 0x04])
+            + &record(END_OF_FILE, 0, &[]);
+
+        let program = read(&text, Endian::Big, Addressing::Byte).unwrap();
+        assert_eq!(program.segments, vec![Segment::new(0x30020, vec![0x0102_0304])]);
+        assert_eq!(program.entry, 0x30020);
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        // Every byte of a record, checksum included, sums to zero
+        let text = ":04001000DEADBEEFB4\n:00000001FF\n";
+        assert!(read(text, Endian::Big, Addressing::Byte).is_ok());
+
+        // The data record is the first line; there's no address record ahead of it
+        let err = read(&text.replace("EFB4", "EFB5"), Endian::Big, Addressing::Byte).unwrap_err();
+        assert_eq!(err, "Line 1: checksum mismatch");
+
+        let err = read(&text.replace("01FF", "01FE"), Endian::Big, Addressing::Byte).unwrap_err();
+        assert_eq!(err, "Line 2: checksum mismatch");
+    }
+}
@@ -0,0 +1,196 @@
+use crate::generator::ISA_VERSION;
+use crate::program::{Endian, Addressing, Program, Segment};
+use crate::utils::crc32;
+
+// Program image container
+//
+// The header and segment table are always big endian. The payload is every
+// segment's words, one after the other, in the endianness given by the flags.
+//
+// | Offset | Size | Field                                               |
+// |      0 |    4 | Magic ("DBVI")                                      |
+// |      4 |    2 | Format version                                      |
+// |      6 |    2 | ISA version                                         |
+// |      8 |    2 | Flags (bit 0: little endian, bit 1: word addressed) |
+// |     10 |    2 | Segment count                                       |
+// |     12 |    4 | Entry point                                         |
+// |     16 |    4 | CRC-32 of the payload                               |
+// |     20 |   8n | Segments (load address, word count)                 |
+// |        |      | Payload                                             |
+
+pub const MAGIC: &[u8; 4] = b"DBVI";
+pub const FORMAT_VERSION: u16 = 1;
+
+const FLAG_LITTLE_ENDIAN: u16 = 1 << 0;
+const FLAG_WORD_ADDRESSED: u16 = 1 << 1;
+
+const HEADER_SIZE: usize = 20;
+const SEGMENT_ENTRY_SIZE: usize = 8;
+
+/// Writes the program as a container image.
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut flags = 0;
+    if program.endian == Endian::Little {
+        flags |= FLAG_LITTLE_ENDIAN;
+    }
+    if program.addressing == Addressing::Word {
+        flags |= FLAG_WORD_ADDRESSED;
+    }
+
+    let payload: Vec<u8> = program.segments.iter().flat_map(|segment| program.segment_bytes(segment)).collect();
+
+    let mut output = Vec::new();
+    output.extend(MAGIC);
+    output.extend(FORMAT_VERSION.to_be_bytes());
+    output.extend(ISA_VERSION.to_be_bytes());
+    output.extend(flags.to_be_bytes());
+    output.extend((program.segments.len() as u16).to_be_bytes());
+    output.extend(program.entry.to_be_bytes());
+    output.extend(crc32(&payload).to_be_bytes());
+
+    for segment in &program.segments {
+        output.extend(segment.origin.to_be_bytes());
+        output.extend((segment.words.len() as u32).to_be_bytes());
+    }
+
+    output.extend(payload);
+    output
+}
+
+/// Returns true if the bytes start with the container's magic.
+pub fn is_image(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// Reads a container image. The layout comes from the header, so there's no
+/// need to pass the endianness or addressing.
+pub fn read(bytes: &[u8]) -> Result<Program, String> {
+    if !is_image(bytes) {
+        return Err(String::from("Missing image magic"));
+    }
+
+    if bytes.len() < HEADER_SIZE {
+        return Err(String::from("Image header is truncated"));
+    }
+
+    let format_version = read_u16(bytes, 4);
+    if format_version != FORMAT_VERSION {
+        return Err(format!("Unsupported image format version {} (expected {})", format_version, FORMAT_VERSION));
+    }
+
+    let isa_version = read_u16(bytes, 6);
+    if isa_version != ISA_VERSION {
+        return Err(format!("Image was built for ISA version {}, but this is version {}", isa_version, ISA_VERSION));
+    }
+
+    let flags = read_u16(bytes, 8);
+    let endian = if flags & FLAG_LITTLE_ENDIAN != 0 { Endian::Little } else { Endian::Big };
+    let addressing = if flags & FLAG_WORD_ADDRESSED != 0 { Addressing::Word } else { Addressing::Byte };
+
+    let segment_count = read_u16(bytes, 10) as usize;
+    let entry = read_u32(bytes, 12);
+    let crc = read_u32(bytes, 16);
+
+    let table_end = HEADER_SIZE + segment_count * SEGMENT_ENTRY_SIZE;
+    if bytes.len() < table_end {
+        return Err(String::from("Image segment table is truncated"));
+    }
+
+    let payload = &bytes[table_end..];
+    if crc32(payload) != crc {
+        return Err(format!("Payload CRC mismatch (expected {:08X}, found {:08X})", crc, crc32(payload)));
+    }
+
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    for i in 0..segment_count {
+        let entry_offset = HEADER_SIZE + i * SEGMENT_ENTRY_SIZE;
+        let origin = read_u32(bytes, entry_offset);
+        let size = read_u32(bytes, entry_offset + 4) as usize * 4;
+
+        if offset + size > payload.len() {
+            return Err(format!("Segment at {:#X} runs past the end of the image", origin));
+        }
+
+        let words = Program::bytes_to_words(&payload[offset..offset + size], endian)?;
+        segments.push(Segment::new(origin, words));
+        offset += size;
+    }
+
+    if offset != payload.len() {
+        return Err(format!("Image has {} bytes after the last segment", payload.len() - offset));
+    }
+
+    Ok(Program::new(segments, entry, endian, addressing))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(endian: Endian, addressing: Addressing) -> Program {
+        let segments = vec![
+            Segment::new(0x100, vec![0x1122_3344, 0x5566_7788]),
+            Segment::new(0x4000, vec![0xDEAD_BEEF]),
+        ];
+        Program::new(segments, 0x104, endian, addressing)
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        for addressing in [Addressing::Byte, Addressing::Word] {
+            for endian in [Endian::Big, Endian::Little] {
+                let original = program(endian, addressing);
+
+                let read = read(&write(&original)).unwrap();
+                assert_eq!(read.segments, original.segments);
+                assert_eq!(read.entry, original.entry);
+                assert_eq!(read.endian, endian);
+                assert_eq!(read.addressing, addressing);
+            }
+        }
+    }
+
+    #[test]
+    fn header_layout() {
+        let bytes = write(&program(Endian::Little, Addressing::Word));
+
+        assert_eq!(&bytes[..4], b"DBVI");
+        assert_eq!(read_u16(&bytes, 8), FLAG_LITTLE_ENDIAN | FLAG_WORD_ADDRESSED);
+        assert_eq!(read_u16(&bytes, 10), 2);
+        assert_eq!(read_u32(&bytes, 12), 0x104);
+        assert_eq!(read_u32(&bytes, 16), crc32(&bytes[HEADER_SIZE + 2 * SEGMENT_ENTRY_SIZE..]));
+        assert_eq!(bytes.len(), HEADER_SIZE + 2 * SEGMENT_ENTRY_SIZE + 3 * 4);
+    }
+
+    #[test]
+    fn corrupted_payload_is_rejected() {
+        let mut bytes = write(&program(Endian::Big, Addressing::Byte));
+        let payload = HEADER_SIZE + 2 * SEGMENT_ENTRY_SIZE;
+        let crc = read_u32(&bytes, 16);
+
+        // Flip one bit of the last word
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+
+        let err = read(&bytes).unwrap_err();
+        assert_eq!(err, format!("Payload CRC mismatch (expected {:08X}, found {:08X})", crc, crc32(&bytes[payload..])));
+    }
+
+    #[test]
+    fn truncated_image_is_rejected() {
+        let bytes = write(&program(Endian::Big, Addressing::Byte));
+
+        assert_eq!(read(&bytes[..12]).unwrap_err(), "Image header is truncated");
+        assert_eq!(read(&bytes[..HEADER_SIZE + 4]).unwrap_err(), "Image segment table is truncated");
+        assert_eq!(read(&bytes[4..]).unwrap_err(), "Missing image magic");
+    }
+}
@@ -1,17 +1,21 @@
-use crate::{enum_conv_gen, utils::Parameter};
+use crate::enum_conv_gen;
 
 // Instruction Design:
 //
 // First 8 bits are the instruction (Opcode)
-// Next four bits are the mode (Register, Value, Memory)
-// The rest of the bits are the arguments
-// | 0000_0000 | 0000 | 0000_0000_0000_0000_0000 | -> 32 bits
-// |  Opcode   | Mode |         Arguments        |
+// Next two bits are the mode (Register, Immediate, RegisterIndirect, BaseOffset)
+// Then the extension flag and the operand count, followed by four operand nibbles
+// | 0000_0000 | 00 | 0 | 000 | 00 | 0000_0000_0000_0000 | -> 32 bits
+// |  Opcode   |Mode| E |Count|Rsvd|      Operands       |
 //
 // We get the opcode, mode and arguemnts through bit masking
 // Opcode: 0xFF000000
-// Mode: 0x00F00000
-// Arguments: 0x000FFFFF
+// Mode: 0x00C00000
+// Extension: 0x00200000 (the value operand is in the next word)
+// Count: 0x001C0000
+// Operands: 0x0000FFFF
+//
+// See src/generator.rs for the full encoding rules
 //
 // It's then up to the instruction to parse the arguments
 // and do what it needs to do
@@ -19,6 +23,7 @@ use crate::{enum_conv_gen, utils::Parameter};
 
 enum_conv_gen! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[allow(clippy::upper_case_acronyms)]
     pub enum Instructions {
         HLT = 0x0,// Halts the program
         
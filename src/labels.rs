@@ -0,0 +1,388 @@
+use std::collections::HashSet;
+
+use crate::ast::{DirectiveKind, Expr, Operand, Span, Statement};
+use crate::instructions::Instructions;
+use crate::pseudo::is_pseudo;
+use crate::registers::register_number;
+
+// Label scoping
+//
+// Runs before layout and gives every label one name that's unique in the file:
+//
+//   Local labels       `.loop:` or `@loop:` belong to the global label before them,
+//                      so after `main:` both are `main.loop`. They can be used from
+//                      anywhere as `main.loop`, or as `.loop` / `@loop` in scope.
+//   Anonymous labels   `-:` and `+:`. `-` refers to the closest `-:` before it and
+//                      `+` to the closest `+:` after it. `--` and `++` skip one,
+//                      and so on.
+//   Numeric labels     `1:` can be defined any number of times. `1b` refers to the
+//                      closest `1:` before it, `1f` to the closest one after it.
+//   Procedure labels   Labels between `.proc name` and `.endproc` are named
+//                      `name::label`. Inside the procedure they can be used as just
+//                      `label`, from outside only as `name::label`. Procedures can
+//                      be nested, giving `outer::inner::label`.
+//   Constants          Members of `.struct Name` and `.enum Name` are named
+//                      `Name.member`, and a structure's size `Name.size`.
+//
+// Anonymous and numeric labels get names with a '#', which can't be written in
+// source, so they never clash with anything else.
+//
+// Names aren't case sensitive unless `--case-sensitive` is given: they're all
+// upper-cased here, so `loop` and `LOOP` are the same label. Either way a label
+// can't have the name of an instruction, a register or a `.req` alias, since
+// `JMP add` or `SET Ra, sp` would then be ambiguous. See src/lexer.rs for which
+// characters a name can use.
+
+fn is_local(name: &str) -> bool {
+    name.starts_with('.') || name.starts_with('@')
+}
+
+fn is_anonymous(name: &str) -> bool {
+    name == "+" || name == "-"
+}
+
+fn is_numeric(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_digit())
+}
+
+/// A reference to an anonymous or numeric label: the definition name, whether
+/// it looks forward and how many definitions to skip.
+fn relative_reference(name: &str) -> Option<(&str, bool, usize)> {
+    if !name.is_empty() && name.chars().all(|c| c == '+') {
+        return Some(("+", true, name.len() - 1));
+    }
+    if !name.is_empty() && name.chars().all(|c| c == '-') {
+        return Some(("-", false, name.len() - 1));
+    }
+
+    let (digits, direction) = name.split_at(name.len().checked_sub(1)?);
+    match direction {
+        "f" if is_numeric(digits) => Some((digits, true, 0)),
+        "b" if is_numeric(digits) => Some((digits, false, 0)),
+        _ => None,
+    }
+}
+
+/// `name` inside the procedure `namespace`, if there is one.
+fn qualify(namespace: Option<&String>, name: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("{}::{}", namespace, name),
+        None => name.to_string(),
+    }
+}
+
+/// Whether the assembler made the name up: anonymous and numeric labels, and
+/// the ones `.if` and `.while` are lowered to (see src/control.rs). They only
+/// mean something inside their own file, so a program never exports them.
+pub fn is_internal(name: &str) -> bool {
+    name.contains('#')
+}
+
+struct Scoper {
+    scopes: Vec<Option<String>>,       // Global label each statement is under
+    namespaces: Vec<Vec<String>>,      // Procedures each statement is in, outermost first
+    definitions: Vec<(String, usize)>, // Anonymous and numeric labels, statement index
+    defined: HashSet<String>,          // Every global and procedure label, normalized
+    case_sensitive: bool,
+}
+
+impl Scoper {
+    /// The name as it's stored, upper-cased unless names are case sensitive.
+    fn normalize(&self, name: &str) -> String {
+        if self.case_sensitive { name.to_string() } else { name.to_uppercase() }
+    }
+
+    /// The file-wide name of a label used in the statement at `index`.
+    fn resolve(&self, name: &str, index: usize, span: &Span) -> String {
+        let resolved = self.resolve_raw(name, index, span);
+        self.normalize(&resolved)
+    }
+
+    fn resolve_raw(&self, name: &str, index: usize, span: &Span) -> String {
+        if let Some((label, forward, skip)) = relative_reference(name) {
+            // Definitions are numbered in order, which is also how they're named
+            let candidates = self.definitions.iter()
+                .enumerate()
+                .filter(|(_, (definition, _))| definition == label);
+
+            let found = if forward {
+                candidates.filter(|(_, (_, at))| *at > index).nth(skip)
+            } else {
+                candidates.filter(|(_, (_, at))| *at <= index).rev().nth(skip)
+            };
+
+            return match found {
+                Some((number, _)) => format!("{}#{}", label, number),
+                None => panic!("{}: No {}: label {} this line for {}", span, label, if forward { "after" } else { "before" }, name),
+            };
+        }
+
+        if is_local(name) {
+            return match &self.scopes[index] {
+                Some(scope) => format!("{}.{}", scope, &name[1..]),
+                None => panic!("{}: Local label {} needs a global label before it", span, name),
+            };
+        }
+
+        // The innermost procedure that defines the name wins, then the file
+        for namespace in self.namespaces[index].iter().rev() {
+            let qualified = qualify(Some(namespace), name);
+            if self.defined.contains(&self.normalize(&qualified)) {
+                return qualified;
+            }
+        }
+
+        name.to_string()
+    }
+}
+
+/// Panics if a label would have the same name as an instruction or register.
+fn check_name(name: &str, aliases: &HashSet<String>, span: &Span) {
+    if Instructions::try_from(name.to_string()).is_ok() || is_pseudo(name) {
+        panic!("{}: Label {} has the same name as an instruction", span, name);
+    }
+    if register_number(name).is_some() {
+        panic!("{}: Label {} has the same name as a register", span, name);
+    }
+    if aliases.contains(&name.to_uppercase()) {
+        panic!("{}: Label {} has the same name as a register alias", span, name);
+    }
+}
+
+/// Renames every label definition and reference to its file-wide name.
+pub fn scope_labels(statements: &[Statement], case_sensitive: bool) -> Vec<Statement> {
+    let mut scoper = Scoper { scopes: Vec::new(), namespaces: Vec::new(), definitions: Vec::new(), defined: HashSet::new(), case_sensitive };
+    let mut qualified_names = Vec::new(); // Definition name of each label and `.proc`
+    let mut aliases = HashSet::new();    // Register aliases from `.req`, upper-cased
+
+    let mut scope = None;
+    let mut procedures: Vec<(String, Option<String>, &Span)> = Vec::new(); // Name, scope before it, `.proc`
+    for (index, statement) in statements.iter().enumerate() {
+        let namespace = procedures.last().map(|(name, _, _)| name);
+        let mut name = None;
+
+        match statement {
+            Statement::Label(label) if label.name.contains("::") => {
+                panic!("{}: Label names can't contain '::', use .proc instead", label.span);
+            },
+            Statement::Label(label) if is_anonymous(&label.name) || is_numeric(&label.name) => {
+                scoper.definitions.push((label.name.clone(), index));
+            },
+            Statement::Label(label) if !is_local(&label.name) => {
+                check_name(&label.name, &aliases, &label.span);
+                let qualified = qualify(namespace, &label.name);
+                scope = Some(qualified.clone());
+                name = Some(qualified);
+            },
+            Statement::Directive(directive) => match &directive.kind {
+                DirectiveKind::Proc(proc_name) => {
+                    if proc_name.contains("::") || is_local(proc_name) {
+                        panic!("{}: Invalid procedure name: {}", directive.span, proc_name);
+                    }
+                    check_name(proc_name, &aliases, &directive.span);
+                    let qualified = qualify(namespace, proc_name);
+                    procedures.push((qualified.clone(), scope.clone(), &directive.span));
+                    scope = Some(qualified.clone());
+                    name = Some(qualified);
+                },
+                DirectiveKind::Struct(layout) | DirectiveKind::Enum(layout) => check_name(layout, &aliases, &directive.span),
+                DirectiveKind::EndProc => match procedures.pop() {
+                    Some((_, outer, _)) => scope = outer,
+                    None => panic!("{}: .endproc without a .proc", directive.span),
+                },
+                DirectiveKind::Req(alias, _) => {
+                    aliases.insert(alias.to_uppercase());
+                },
+                DirectiveKind::Unreq(alias) => {
+                    aliases.remove(&alias.to_uppercase());
+                },
+                _ => {},
+            },
+            _ => {},
+        }
+
+        let name = name.map(|name| scoper.normalize(&name));
+        if let Some(name) = &name {
+            scoper.defined.insert(name.clone());
+        }
+        qualified_names.push(name);
+        scoper.scopes.push(scope.clone());
+        scoper.namespaces.push(procedures.iter().map(|(name, _, _)| name.clone()).collect());
+    }
+
+    if let Some((name, _, span)) = procedures.last() {
+        panic!("{}: .proc {} is never closed with .endproc", span, name);
+    }
+
+    let mut anonymous = 0;
+    let mut layout = String::new(); // `.struct` or `.enum` the members belong to
+    statements.iter().enumerate().map(|(index, statement)| {
+        let mut statement = statement.clone();
+        match &mut statement {
+            Statement::Label(label) => {
+                if is_anonymous(&label.name) || is_numeric(&label.name) {
+                    label.name = format!("{}#{}", label.name, anonymous);
+                    anonymous += 1;
+                } else {
+                    label.name = match &qualified_names[index] {
+                        Some(name) => name.clone(),
+                        None => scoper.resolve(&label.name, index, &label.span),
+                    };
+                }
+            },
+            Statement::Instruction(instruction) => {
+                for operand in &mut instruction.operands {
+                    if let Operand::Immediate(Expr::Symbol(name)) | Operand::BaseOffset(_, Expr::Symbol(name)) = operand {
+                        *name = scoper.resolve(name, index, &instruction.span);
+                    }
+                }
+            },
+            Statement::Directive(directive) => match &mut directive.kind {
+                DirectiveKind::Word(values) => {
+                    for value in values {
+                        if let Expr::Symbol(name) = value {
+                            *name = scoper.resolve(name, index, &directive.span);
+                        }
+                    }
+                },
+                DirectiveKind::Entry(name) => *name = scoper.resolve(name, index, &directive.span),
+                DirectiveKind::Global(names) => {
+                    for name in names {
+                        *name = scoper.resolve(name, index, &directive.span);
+                    }
+                },
+                DirectiveKind::If(condition) | DirectiveKind::While(condition) => {
+                    if let Operand::Immediate(Expr::Symbol(name)) | Operand::BaseOffset(_, Expr::Symbol(name)) = &mut condition.right {
+                        *name = scoper.resolve(name, index, &directive.span);
+                    }
+                },
+                DirectiveKind::Extern(names) => {
+                    for name in names {
+                        *name = scoper.normalize(name);
+                    }
+                },
+                DirectiveKind::Proc(name) => *name = qualified_names[index].clone().unwrap(),
+                DirectiveKind::Struct(name) | DirectiveKind::Enum(name) => {
+                    layout = name.clone();
+                    *name = scoper.normalize(name);
+                },
+                DirectiveKind::Field(name, _) | DirectiveKind::EndStruct(name) | DirectiveKind::EnumMember(name, _) => {
+                    *name = scoper.normalize(&format!("{}.{}", layout, name));
+                },
+                _ => {},
+            },
+        }
+        statement
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::parse;
+
+    fn scoped(source: &str, case_sensitive: bool) -> Vec<Statement> {
+        scope_labels(&parse(&Lexer::new("test.asm").tokenize(source)), case_sensitive)
+    }
+
+    fn labels(statements: &[Statement]) -> Vec<&str> {
+        statements.iter().filter_map(|statement| match statement {
+            Statement::Label(label) => Some(label.name.as_str()),
+            _ => None,
+        }).collect()
+    }
+
+    /// The label each instruction refers to.
+    fn targets(statements: &[Statement]) -> Vec<&str> {
+        statements.iter().filter_map(|statement| match statement {
+            Statement::Instruction(instruction) => instruction.operands.iter().find_map(|operand| match operand {
+                Operand::Immediate(Expr::Symbol(name)) => Some(name.as_str()),
+                _ => None,
+            }),
+            _ => None,
+        }).collect()
+    }
+
+    const PROCEDURES: &str = concat!(
+        ".proc outer\n",
+        "    JMP loop\n",
+        "loop: CALL inner\n",
+        "    .proc inner\n",
+        "    loop: JMP loop\n",
+        "    .endproc\n",
+        "    RET\n",
+        ".endproc\n",
+        "main: CALL outer::inner\n",
+        "    JMP outer::loop\n",
+        "    JMP loop\n",
+    );
+
+    #[test]
+    fn procedure_labels_are_qualified() {
+        let statements = scoped(PROCEDURES, false);
+
+        assert_eq!(labels(&statements), vec!["OUTER::LOOP", "OUTER::INNER::LOOP", "MAIN"]);
+        assert_eq!(targets(&statements), vec![
+            // Inside, the innermost procedure's label wins
+            "OUTER::LOOP", "OUTER::INNER", "OUTER::INNER::LOOP",
+            // Outside only the full name finds them
+            "OUTER::INNER", "OUTER::LOOP", "LOOP",
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:1:1: .proc outer is never closed with .endproc")]
+    fn unclosed_procedure_panics() {
+        scoped(".proc outer\n    RET\n", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:1:1: .endproc without a .proc")]
+    fn endproc_without_proc_panics() {
+        scoped(".endproc\n", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "Label names can't contain '::', use .proc instead")]
+    fn qualified_label_definition_panics() {
+        scoped("outer::loop: RET\n", false);
+    }
+
+    #[test]
+    fn names_ignore_case_unless_asked() {
+        let source = "loop: JMP LOOP\n.again: JMP .AGAIN\n";
+
+        let statements = scoped(source, false);
+        assert_eq!(labels(&statements), vec!["LOOP", "LOOP.AGAIN"]);
+        assert_eq!(targets(&statements), vec!["LOOP", "LOOP.AGAIN"]);
+
+        let statements = scoped(source, true);
+        assert_eq!(labels(&statements), vec!["loop", "loop.again"]);
+        assert_eq!(targets(&statements), vec!["LOOP", "loop.AGAIN"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:1:1: Label add has the same name as an instruction")]
+    fn instruction_name_panics() {
+        scoped("add: RET\n", true);
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:1:1: Label Inc has the same name as an instruction")]
+    fn pseudo_instruction_name_panics() {
+        scoped("Inc: RET\n", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:2:1: Label SP has the same name as a register")]
+    fn register_name_panics() {
+        scoped("main: RET\nSP: RET\n", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:2:1: Label Counter has the same name as a register alias")]
+    fn alias_name_panics() {
+        scoped("counter .req Rc\nCounter: RET\n", false);
+    }
+}
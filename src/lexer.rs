@@ -1,199 +1,233 @@
 // Separate the imports for better clarity
-use crate::Instructions;
-use crate::Token;
-use crate::utils::{cleanup_line, string_to_u32, register_to_byte};
-use crate::instructions::InstructionMode;
+use crate::ast::Span;
+use crate::token::{Token, TokenKind};
+use crate::utils::string_to_u64;
 
-/// Represents a Lexer with labels and clean lines.
+const PUNCTUATION: &[char] = &[',', ':', '[', ']', '+', '-', '(', ')', '=', '!', '<', '>'];
+
+/// Turns source text into tokens, one character at a time.
 pub struct Lexer {
-    pub labels: Vec<(String, u32)>, // Label name, index
-    clean_lines: Vec<Vec<String>>,  // Clean lines generated in the prepass
+    pub file: String, // Source file name, used for spans
 }
 
 impl Lexer {
     /// Creates a new Lexer.
-    pub fn new() -> Self {
-        Lexer {
-            labels: Vec::new(),
-            clean_lines: Vec::new(),
-        }
+    pub fn new(file: &str) -> Self {
+        Lexer { file: file.to_string() }
     }
 
-    /// Preprocesses the input to identify and store labels.
-    pub fn label_prepass(&mut self, input: String) {
-        let mut current_index = 0;
-        for line in input.lines() {
-            let mut clean_line = cleanup_line(line);
-            if self.is_label_line(&clean_line) {
-                self.handle_label_line(&mut clean_line, &mut current_index);
-            } else {
-                self.increment_index_for_non_label_line(&clean_line, &mut current_index);
-            }
+    /// Tokenizes the input. Every line ends with a `Newline` token, even the last
+    /// one. Spaces, tabs and carriage returns only separate tokens.
+    pub fn tokenize(&self, input: &str) -> Vec<Token> {
+        let chars: Vec<char> = input.chars().collect();
+        let mut tokens = Vec::new();
+        let mut index = 0;
+        let mut line = 1;
+        let mut line_start = 0; // Index of the first character of the line
+
+        while index < chars.len() {
+            let c = chars[index];
+            let start = index;
+            let span = move |end: usize| Span { file: self.file.clone(), line, column: start - line_start + 1, length: end - start };
+
+            let kind = match c {
+                '\n' => {
+                    tokens.push(Token { kind: TokenKind::Newline, span: span(start + 1) });
+                    index += 1;
+                    line += 1;
+                    line_start = index;
+                    continue;
+                },
+                c if c.is_whitespace() => {
+                    index += 1;
+                    continue;
+                },
+                ';' => {
+                    while index < chars.len() && chars[index] != '\n' {
+                        index += 1;
+                    }
+                    let text: String = chars[start + 1..index].iter().collect();
+                    TokenKind::Comment(text.trim_end_matches('\r').to_string())
+                },
+                '"' => {
+                    index += 1;
+                    let mut text = String::new();
+                    loop {
+                        match chars.get(index) {
+                            Some('"') => break,
+                            Some('\\') => {
+                                let escaped = match chars.get(index + 1) {
+                                    Some('n') => '\n',
+                                    Some('t') => '\t',
+                                    Some('0') => '\0',
+                                    Some('\\') => '\\',
+                                    Some('"') => '"',
+                                    other => panic!("{}: Invalid escape in string: \\{}", span(index), other.map_or(String::new(), |c| c.to_string())),
+                                };
+                                text.push(escaped);
+                                index += 2;
+                            },
+                            Some('\n') | None => panic!("{}: Unterminated string", span(index)),
+                            Some(c) => {
+                                text.push(*c);
+                                index += 1;
+                            },
+                        }
+                    }
+                    index += 1;
+                    TokenKind::String(text)
+                },
+                c if c.is_ascii_digit() => {
+                    while index < chars.len() && (chars[index].is_ascii_alphanumeric() || chars[index] == '_') {
+                        index += 1;
+                    }
+                    let text: String = chars[start..index].iter().filter(|c| **c != '_').collect();
+
+                    // A decimal number followed by a fraction is a float
+                    let is_float = text.chars().all(|c| c.is_ascii_digit())
+                        && chars.get(index) == Some(&'.')
+                        && chars.get(index + 1).is_some_and(char::is_ascii_digit);
+                    if is_float {
+                        index = float_end(&chars, index);
+                        let text: String = chars[start..index].iter().filter(|c| **c != '_').collect();
+                        match text.parse::<f64>() {
+                            Ok(value) => TokenKind::Float(value),
+                            Err(_) => panic!("{}: Invalid number: {}", span(index), text),
+                        }
+                    } else {
+                        match string_to_u64(&text) {
+                            Some(value) => TokenKind::Number(value),
+                            // `1f` and `1b` refer to numeric labels
+                            None if is_numeric_label_reference(&text) => TokenKind::Identifier(text),
+                            None => panic!("{}: Invalid number: {}", span(index), text),
+                        }
+                    }
+                },
+                c if is_identifier_start(c) => {
+                    index += 1;
+                    loop {
+                        match chars.get(index..index + 3) {
+                            // `proc::label`, see src/labels.rs
+                            Some([':', ':', c]) if is_identifier_char(*c) => index += 2,
+                            _ if index < chars.len() && is_identifier_char(chars[index]) => index += 1,
+                            _ => break,
+                        }
+                    }
+                    TokenKind::Identifier(chars[start..index].iter().collect())
+                },
+                c if PUNCTUATION.contains(&c) => {
+                    index += 1;
+                    TokenKind::Punctuation(c)
+                },
+                c => panic!("{}: Unexpected character: {:?}", span(start + 1), c),
+            };
 
-            println!("Read Line: {:?}", clean_line);
-            self.add_clean_line(clean_line);
+            tokens.push(Token { kind, span: span(index) });
         }
-    }
 
-    /// Determines if the given line is a label line.
-    fn is_label_line(&self, clean_line: &[String]) -> bool {
-        !clean_line.is_empty() && clean_line[0].contains(":")
-    }
+        if !matches!(tokens.last(), None | Some(Token { kind: TokenKind::Newline, .. })) {
+            let span = Span { file: self.file.clone(), line, column: index - line_start + 1, length: 0 };
+            tokens.push(Token { kind: TokenKind::Newline, span });
+        }
 
-    /// Handles label lines by storing the label and updating the index.
-    fn handle_label_line(&mut self, clean_line: &mut Vec<String>, current_index: &mut u32) {
-        let token = clean_line[0].replace(":", "");
-        self.labels.push((token.to_uppercase(), *current_index));
-        clean_line.remove(0);
+        tokens
     }
+}
 
-    /// Increments the index for non-label lines.
-    fn increment_index_for_non_label_line(&mut self, clean_line: &[String], current_index: &mut u32) {
-        if !clean_line.is_empty() {
-            *current_index += 4; // 4 bytes per instruction
-        }
-    }
+// Identifiers
+//
+// A name starts with a letter, '_', '.' or '@' and goes on with letters, digits,
+// '_' and '.'. Only ASCII letters count. `::` joins two names (`proc::label`).
+// Mnemonics, registers and directives follow the same rules as labels, what a
+// name means is up to the parser.
 
-    fn add_clean_line(&mut self, clean_line: Vec<String>) {
-        if !clean_line.is_empty() {
-            self.clean_lines.push(clean_line);
-        }
-    }
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '@'
 }
 
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
 
-impl Lexer{
-    fn process_register(&self, item: &str) -> Option<u32> {
-        let item = item.replace("R", "");
-        register_to_byte(&item)
+/// Index just past the fraction and exponent of a float, from its '.'.
+fn float_end(chars: &[char], mut index: usize) -> usize {
+    index += 1;
+    while index < chars.len() && (chars[index].is_ascii_digit() || chars[index] == '_') {
+        index += 1;
     }
-    
-    fn process_memory_address(&self, last_value: &str) -> Option<Token> {
-        let last_value = last_value.replace("[", "").replace("]", "");
-    
-        if last_value.contains("+") {
-            let split_value: Vec<&str> = last_value.split("+").collect();
-            let register = self.process_register(split_value[0])?;
-            let offset = string_to_u32(split_value[1])?;
-            Some(Token::BaseOffset(register, offset))
-        } else {
-            let register = self.process_register(&last_value)?;
-            Some(Token::Register(register))
+
+    if matches!(chars.get(index), Some('e' | 'E')) {
+        let digits = if matches!(chars.get(index + 1), Some('+' | '-')) { index + 2 } else { index + 1 };
+        if chars.get(digits).is_some_and(char::is_ascii_digit) {
+            index = digits;
+            while index < chars.len() && chars[index].is_ascii_digit() {
+                index += 1;
+            }
         }
     }
 
-    pub fn tokenize(&mut self, input: String) -> Vec<Vec<Token>> {
-        let mut tokens = Vec::new();
-        
-        // Prepass to find all the labels
-        self.label_prepass(input);
-
-        for line in &self.clean_lines {
-            let mut line_tokens = Vec::new();
-
-            let len = line.len();
+    index
+}
 
+fn is_numeric_label_reference(text: &str) -> bool {
+    match text.strip_suffix(['f', 'b']) {
+        Some(digits) => !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
 
-            if len > 4{
-                // Too many tokens
-                panic!("Too many tokens in line: {} -- {:?}", line.join(" "), line);
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // The first token in the line is the instruction
-            // We need to convert this to an OpCode. Check src/instructions.rs for more info
-            let instruction = match Instructions::try_from(line[0].clone()){
-                Ok(op_code) => op_code,
-                Err(_) => panic!("Invalid instruction: {}", line[0]),
-            };
+    fn kinds(input: &str) -> Vec<TokenKind> {
+        Lexer::new("test.asm").tokenize(input).into_iter().map(|token| token.kind).collect()
+    }
 
-            line_tokens.push(Token::OpCode(instruction));
-
-            // The rest of the tokens are parameters. 
-            // There are different modes,
-            // so we need to check which mode we are in. This will be determined
-            // by the parameters themselves. The last parameter
-            // will always determine the mode.
-
-            // Example instruction:
-            // ADD R1, R2, R3 -> Register mode
-            // ADD R1, R2, 0x00000001 -> Immediate mode
-            // ADD R1, R2, [R3] -> Register Indirect mode
-            // ADD R1, R2, [R3 + 0x00000001] -> Base Offset mode
-            //
-            // It's key to note the first parameter is always the destination register
-            // The second parameter is always the first source register. This can't
-            // be a value or memory address
-            // The third parameter is always the second source register, value or memory address
-            // Note - not every instruction has 3 parameters. Some have 2, some have 1
-            // However, each instruction's last parameter will always determine the mode
-
-            if len == 1{
-                line_tokens.push(Token::Mode(InstructionMode::Register));
-                tokens.push(line_tokens);
-                continue;
-            }
-            
-            for item in &line[1..len-1] {
-                if let Some(register) = self.process_register(item) {
-                    line_tokens.push(Token::Register(register));
-                } else {
-                    panic!("Invalid register: {}", item);
-                }
-            }
-        
-            let last_value = &line[len - 1];
-            if last_value.contains("[") && last_value.contains("]") {
-                match self.process_memory_address(&last_value) {
-                    Some(Token::BaseOffset(register, offset)) => {
-                        line_tokens.push(Token::BaseOffset(register, offset));
-                        line_tokens.insert(1, Token::Mode(InstructionMode::BaseOffset));
-                    },
-                    Some(Token::Register(register)) => {
-                        line_tokens.push(Token::Register(register));
-                        line_tokens.insert(1, Token::Mode(InstructionMode::RegisterIndirect));
-                    },
-                    _ => panic!("Invalid memory address: {}", last_value),
-                }
-            } else {
-                match string_to_u32(&last_value) {
-                    Some(value) => {
-                        line_tokens.push(Token::Value(value));
-                        line_tokens.insert(1, Token::Mode(InstructionMode::Immediate));
-                    },
-                    None => {
-                        let check_reg = last_value.replace("R", "");
-                        match register_to_byte(&check_reg) {
-                            Some(register) => {
-                                line_tokens.push(Token::Register(register));
-                                line_tokens.insert(1, Token::Mode(InstructionMode::Register));
-                            },
-                            None => {
-                                println!("Assuming {} is a label", last_value);
-                                line_tokens.push(Token::Label(last_value.to_string()));
-                                line_tokens.insert(1, Token::Mode(InstructionMode::Immediate));
-                            },
-                        }
-                    }
-                }
-            }
+    fn identifier(name: &str) -> TokenKind {
+        TokenKind::Identifier(name.to_string())
+    }
 
-            tokens.push(line_tokens);
-        }
+    #[test]
+    fn tabs_separate_tokens() {
+        assert_eq!(kinds("\tADD\tRa,\tRb"), kinds("ADD Ra, Rb"));
+    }
 
+    #[test]
+    fn comma_needs_no_space() {
+        let expected = vec![identifier("ADD"), identifier("Ra"), TokenKind::Punctuation(','), identifier("Rb"), TokenKind::Newline];
+        assert_eq!(kinds("ADD Ra,Rb"), expected);
+        assert_eq!(kinds("ADD Ra , Rb"), expected);
+    }
 
-        tokens
+    #[test]
+    fn spaces_inside_brackets() {
+        let expected = vec![
+            identifier("LD"), identifier("Ra"), TokenKind::Punctuation(','),
+            TokenKind::Punctuation('['), identifier("Rb"), TokenKind::Punctuation('+'), TokenKind::Number(4), TokenKind::Punctuation(']'),
+            TokenKind::Newline,
+        ];
+        assert_eq!(kinds("LD Ra, [Rb + 4]"), expected);
+        assert_eq!(kinds("LD Ra,[Rb+4]"), expected);
+        assert_eq!(kinds("LD Ra, [ Rb +4 ]"), expected);
     }
 
-    pub fn get_label_address(&self, label: &str) -> Option<u32>{
-        let label = label.to_uppercase();
-        // Iterate through the labels and find the one we want
-        for (label_name, label_address) in &self.labels{
-            println!("Comparing {} to {}", label_name, label);
-            if label_name == &label{
-                return Some(*label_address);
-            }
-        }
+    #[test]
+    fn crlf_line_endings() {
+        let tokens = Lexer::new("test.asm").tokenize("start: INC Ra ; count\r\nHLT\r\n");
+        let kinds: Vec<TokenKind> = tokens.iter().map(|token| token.kind.clone()).collect();
+        assert_eq!(kinds, vec![
+            identifier("start"), TokenKind::Punctuation(':'), identifier("INC"), identifier("Ra"), TokenKind::Comment(" count".to_string()), TokenKind::Newline,
+            identifier("HLT"), TokenKind::Newline,
+        ]);
+
+        let hlt = &tokens[6];
+        assert_eq!((hlt.span.line, hlt.span.column, hlt.span.length), (2, 1, 3));
+    }
 
-        None
+    #[test]
+    fn names_stay_whole() {
+        // Only the parser decides what's a register, `RR` and `BR` reach it as they are
+        assert_eq!(kinds("SET RR, BR"), vec![identifier("SET"), identifier("RR"), TokenKind::Punctuation(','), identifier("BR"), TokenKind::Newline]);
     }
 }
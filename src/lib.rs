@@ -0,0 +1,36 @@
+// The assembler, linker and tools as a library. src/main.rs is the command line
+// on top of it, anything it does can be done from here too:
+//
+//   let tokens = lexer::Lexer::new("prog.asm").tokenize(&source);
+//   let statements = labels::scope_labels(&parser::parse(&tokens), false);
+//   let graph = cfg::ControlFlowGraph::build(&control::lower_control_flow(&statements));
+
+pub mod instructions;
+pub mod lexer;
+pub mod utils;
+pub mod parser;
+pub mod generator;
+pub mod ast;
+pub mod assembler;
+pub mod labels;
+pub mod control;
+pub mod pseudo;
+pub mod registers;
+pub mod token;
+pub mod program;
+pub mod output;
+pub mod ihex;
+pub mod srec;
+pub mod meminit;
+pub mod embed;
+pub mod image;
+pub mod object;
+pub mod archive;
+pub mod linker;
+pub mod mapfile;
+pub mod memmap;
+pub mod disassembler;
+pub mod debuginfo;
+pub mod formatter;
+pub mod cfg;
+pub mod lint;
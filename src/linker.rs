@@ -0,0 +1,512 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::archive::Archive;
+use crate::labels::is_internal;
+use crate::mapfile::{LinkMap, MapLine, MapRegion, MapSection, MapSymbol};
+use crate::memmap::MemoryMap;
+use crate::object::{Object, Symbol};
+use crate::program::{Endian, Program, Segment};
+
+/// A section once it has an address.
+struct Placed {
+    name: String,
+    object: usize,
+    origin: u32,
+    words: Vec<u32>,
+    bss: bool,
+}
+
+/// Adds the archive members needed to define the symbols the objects use but
+/// don't define. Members can need symbols from other members, so this repeats
+/// until nothing new gets pulled in. Symbols nobody defines are left for `link`
+/// to report.
+pub fn pull_members(mut objects: Vec<Object>, archives: &[Archive]) -> Vec<Object> {
+    let mut pulled: HashSet<(usize, usize)> = HashSet::new(); // Archive, member
+
+    loop {
+        let defined: HashSet<&str> = objects.iter()
+            .flat_map(|object| object.symbols.iter().filter(|symbol| symbol.global))
+            .map(|symbol| symbol.name.as_str())
+            .collect();
+
+        let mut needed: Vec<(usize, usize)> = Vec::new();
+        for object in &objects {
+            for relocation in &object.relocations {
+                let symbol = relocation.symbol.as_str();
+                if defined.contains(symbol) || object.symbols.iter().any(|local| local.name == symbol) {
+                    continue;
+                }
+
+                let member = archives.iter().enumerate().find_map(|(archive_index, archive)| {
+                    archive.member_for(symbol).map(|member| (archive_index, member))
+                });
+                if let Some(member) = member.filter(|member| !pulled.contains(member) && !needed.contains(member)) {
+                    needed.push(member);
+                }
+            }
+        }
+
+        if needed.is_empty() {
+            return objects;
+        }
+
+        for (archive_index, member) in needed {
+            pulled.insert((archive_index, member));
+            objects.push(archives[archive_index].members[member].clone());
+        }
+    }
+}
+
+/// Links objects into a program.
+///
+/// Sections with a fixed address (from `.org`) stay where they are. The others
+/// are grouped by name, in the order they first appear, and placed one after the
+/// other: in their memory map region if there is a map, otherwise from address 0.
+///
+/// Every problem found is returned, not just the first one. Along with the
+/// program comes a map of where everything ended up.
+pub fn link(objects: &[Object], endian: Endian, map: Option<&MemoryMap>) -> Result<(Program, LinkMap), Vec<String>> {
+    let mut errors = Vec::new();
+
+    let addressing = match objects.first() {
+        Some(object) => object.addressing,
+        None => return Err(vec![String::from("Nothing to link")]),
+    };
+
+    for object in objects {
+        if object.addressing != addressing {
+            errors.push(format!("{} is {:?} addressed, but {} is {:?} addressed", object.name, object.addressing, objects[0].name, addressing));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let unit = addressing.unit_size();
+    let instruction_size = addressing.instruction_size();
+    let section_size = |words: &Vec<u32>| words.len() as u32 * instruction_size;
+
+    // Lay out the sections, `placement[object][section]` is an index into `placed`
+    let mut placed: Vec<Placed> = Vec::new();
+    let mut placement: Vec<Vec<usize>> = objects.iter().map(|object| vec![0; object.sections.len()]).collect();
+
+    for (object_index, object) in objects.iter().enumerate() {
+        for (section_index, section) in object.sections.iter().enumerate() {
+            if let Some(origin) = section.origin {
+                placement[object_index][section_index] = placed.len();
+                placed.push(Placed { name: section.name.clone(), object: object_index, origin, words: section.words.clone(), bss: section.bss });
+            }
+        }
+    }
+
+    let mut section_names: Vec<&str> = Vec::new();
+    for object in objects {
+        for section in object.sections.iter().filter(|section| section.origin.is_none()) {
+            if !section_names.contains(&section.name.as_str()) {
+                section_names.push(&section.name);
+            }
+        }
+    }
+
+    let mut cursors: HashMap<&str, u64> = HashMap::new(); // Region, next free address
+    let mut region_sections: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut next_free: u64 = 0;
+    for name in &section_names {
+        let region = match map {
+            Some(map) => match map.region_for(name) {
+                Some(region) => Some(region),
+                None => {
+                    errors.push(format!("Section {} isn't placed in any region of the memory map", name));
+                    continue;
+                },
+            },
+            None => None,
+        };
+
+        let cursor = match region {
+            Some(region) => {
+                region_sections.entry(&region.name).or_default().push(name);
+                cursors.entry(&region.name).or_insert(region.origin as u64)
+            },
+            None => &mut next_free,
+        };
+
+        for (object_index, object) in objects.iter().enumerate() {
+            for (section_index, section) in object.sections.iter().enumerate() {
+                if section.origin.is_none() && section.name == *name {
+                    placement[object_index][section_index] = placed.len();
+                    placed.push(Placed { name: section.name.clone(), object: object_index, origin: *cursor as u32, words: section.words.clone(), bss: section.bss });
+                    *cursor += section_size(&section.words) as u64;
+                }
+            }
+        }
+    }
+
+    if let Some(map) = map {
+        for region in &map.regions {
+            let end = cursors.get(region.name.as_str()).copied().unwrap_or(region.origin as u64);
+            if end > region.end() {
+                let sections = region_sections[region.name.as_str()].join(", ");
+                errors.push(format!("Region {} overflows by {} bytes ({})", region.name, (end - region.end()) * unit as u64, sections));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // Nothing may overlap
+    let mut order: Vec<usize> = (0..placed.len()).filter(|i| !placed[*i].words.is_empty()).collect();
+    order.sort_by_key(|i| placed[*i].origin);
+    for pair in order.windows(2) {
+        let (first, second) = (&placed[pair[0]], &placed[pair[1]]);
+        if first.origin as u64 + section_size(&first.words) as u64 > second.origin as u64 {
+            errors.push(format!("Section {} at {:#X} overlaps section {} at {:#X}", second.name, second.origin, first.name, first.origin));
+        }
+    }
+
+    let address_of = |object_index: usize, section: u32, offset: u32| -> u32 {
+        placed[placement[object_index][section as usize]].origin + offset
+    };
+
+    // Collect the exported symbols
+    let mut globals: HashMap<&str, (usize, u32)> = HashMap::new(); // Name, (object, address)
+    for (object_index, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            let address = address_of(object_index, symbol.section, symbol.offset);
+            match globals.get(symbol.name.as_str()) {
+                Some((other, _)) => errors.push(format!("Duplicate symbol {} defined in {} and {}", symbol.name, objects[*other].name, object.name)),
+                None => {
+                    globals.insert(&symbol.name, (object_index, address));
+                },
+            }
+        }
+    }
+
+    // A symbol is looked up in its own object first, then in the exports
+    let resolve = |object_index: usize, name: &str| -> Option<u32> {
+        let object = &objects[object_index];
+        object.symbols.iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| address_of(object_index, symbol.section, symbol.offset))
+            .or_else(|| globals.get(name).map(|(_, address)| *address))
+    };
+
+    let mut patches = Vec::new(); // Placed section, word, value
+    for (object_index, object) in objects.iter().enumerate() {
+        for relocation in &object.relocations {
+            match resolve(object_index, &relocation.symbol) {
+                Some(address) => {
+                    let section = placement[object_index][relocation.section as usize];
+                    patches.push((section, (relocation.offset / instruction_size) as usize, address));
+                },
+                None => errors.push(format!("Undefined symbol {} referenced in {}", relocation.symbol, object.name)),
+            }
+        }
+    }
+
+    let mut entry = None;
+    let entries: Vec<usize> = (0..objects.len()).filter(|i| objects[*i].entry.is_some()).collect();
+    if entries.len() > 1 {
+        let names: Vec<&str> = entries.iter().map(|i| objects[*i].name.as_str()).collect();
+        errors.push(format!("Entry point is set in more than one object: {}", names.join(", ")));
+    } else if let Some(object_index) = entries.first() {
+        let name = objects[*object_index].entry.as_ref().unwrap();
+        match resolve(*object_index, name) {
+            Some(address) => entry = Some(address),
+            None => errors.push(format!("Undefined entry symbol {} in {}", name, objects[*object_index].name)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut labels = Vec::new();
+    let mut symbols = Vec::new();
+    for (object_index, object) in objects.iter().enumerate() {
+        // Generated labels never leave their object, they aren't in the map either
+        let named: Vec<&Symbol> = object.symbols.iter().filter(|symbol| !is_internal(&symbol.name)).collect();
+        for symbol in &named {
+            let section = &placed[placement[object_index][symbol.section as usize]];
+            let address = section.origin + symbol.offset;
+
+            // Symbols reach up to the next one in the same section, procedures
+            // know their size
+            let end = named.iter()
+                .filter(|other| other.section == symbol.section && other.offset > symbol.offset)
+                .map(|other| section.origin + other.offset)
+                .min()
+                .unwrap_or(section.origin + section_size(&section.words));
+
+            // The program exports the labels its file named, or with several
+            // objects only the `.global` ones: the others belong to their object
+            // and can repeat between objects
+            if symbol.global || objects.len() == 1 {
+                labels.push((symbol.name.clone(), address));
+            }
+            symbols.push(MapSymbol {
+                name: symbol.name.clone(),
+                address,
+                size: symbol.size.unwrap_or(end - address),
+                section: section.name.clone(),
+                file: object.source.clone(),
+                line: symbol.line,
+                global: symbol.global,
+                procedure: symbol.size.is_some(),
+            });
+        }
+    }
+    symbols.sort_by(|a, b| a.address.cmp(&b.address).then(a.name.cmp(&b.name)));
+
+    let mut link_map = LinkMap {
+        sections: placed.iter().map(|section| MapSection {
+            name: section.name.clone(),
+            object: objects[section.object].name.clone(),
+            origin: section.origin,
+            size: section_size(&section.words),
+            bss: section.bss,
+        }).collect(),
+        symbols,
+        regions: Vec::new(),
+        lines: Vec::new(),
+    };
+    link_map.sections.sort_by_key(|section| section.origin);
+
+    for (object_index, object) in objects.iter().enumerate() {
+        link_map.lines.extend(object.lines.iter().map(|entry| MapLine {
+            address: address_of(object_index, entry.section, entry.offset),
+            file: object.source.clone(),
+            line: entry.line,
+            column: entry.column,
+            expansion: entry.expansion.clone(),
+        }));
+    }
+    link_map.lines.sort_by_key(|line| line.address);
+
+    if let Some(map) = map {
+        link_map.regions = map.regions.iter().map(|region| MapRegion {
+            name: region.name.clone(),
+            origin: region.origin,
+            length: region.length,
+            used: link_map.sections.iter()
+                .filter(|section| section.origin >= region.origin && (section.origin as u64) < region.end())
+                .map(|section| section.size)
+                .sum(),
+        }).collect();
+    }
+
+    for (section, word, value) in patches {
+        placed[section].words[word] = value;
+    }
+
+    // 64-bit values are high word first, little endian wants the low word first
+    if endian == Endian::Little {
+        for (object_index, object) in objects.iter().enumerate() {
+            for double_word in &object.double_words {
+                let section = placement[object_index][double_word.section as usize];
+                let word = (double_word.offset / instruction_size) as usize;
+                placed[section].words.swap(word, word + 1);
+            }
+        }
+    }
+
+    // Only sections with contents end up in the image, neighbours are merged
+    let mut segments: Vec<Segment> = Vec::new();
+    for index in order {
+        let section = &placed[index];
+        if section.bss {
+            continue;
+        }
+
+        match segments.last_mut() {
+            Some(segment) if segment.end(addressing) == section.origin => segment.words.extend(&section.words),
+            _ => segments.push(Segment::new(section.origin, section.words.clone())),
+        }
+    }
+
+    let mut program = Program::new(segments, 0, endian, addressing);
+    program.entry = entry.unwrap_or(program.start());
+    program.labels = labels;
+
+    Ok((program, link_map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::assembler::Assembly;
+    use crate::control::lower_control_flow;
+    use crate::labels::scope_labels;
+    use crate::lexer::Lexer;
+    use crate::parser::parse;
+    use crate::program::Addressing;
+
+    fn object(file: &str, source: &str) -> Object {
+        let statements = lower_control_flow(&scope_labels(&parse(&Lexer::new(file).tokenize(source)), false));
+        Object::assemble(&Assembly::new(file, &statements, Addressing::Byte)).unwrap()
+    }
+
+    #[test]
+    fn generated_labels_stay_in_their_object() {
+        let main = concat!(
+            ".global main\n",
+            ".extern count\n",
+            "main: .if Ra == 0\n",
+            "    CALL count\n",
+            ".endif\n",
+            ".while Rb < 3\n",
+            "    ADD Rb, Rb, 1\n",
+            ".endw\n",
+            "    HLT\n",
+        );
+        let count = concat!(
+            ".global count\n",
+            "count: .if Rb == 1\n",
+            "    SET Ra, 0\n",
+            ".endif\n",
+            ".while Ra < 10\n",
+            "    .if Rc == 1\n",
+            "        .break\n",
+            "    .endif\n",
+            "    ADD Ra, Ra, 1\n",
+            ".endw\n",
+            "-:  RET\n",
+        );
+
+        let objects = [object("main.asm", main), object("count.asm", count)];
+        let (program, _) = link(&objects, Endian::Big, None).unwrap();
+
+        let names: Vec<&str> = program.labels.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["MAIN", "COUNT"]);
+
+        // Each object's `#if0.else` is its own, the jumps to it land inside the object
+        let words = &program.segments[0].words;
+        let mut origin = 0;
+        for object in &objects {
+            let target = object.symbols.iter().find(|symbol| symbol.name == "#if0.else").unwrap();
+            let jump = object.relocations.iter().find(|relocation| relocation.symbol == "#if0.else").unwrap();
+            assert_eq!(words[((origin + jump.offset) / 4) as usize], origin + target.offset);
+            origin += object.sections[0].words.len() as u32 * 4;
+        }
+    }
+
+    fn placements(map: &LinkMap) -> Vec<(&str, &str, u32)> {
+        map.sections.iter().map(|section| (section.name.as_str(), section.object.as_str(), section.origin)).collect()
+    }
+
+    const FIRST: &str = concat!(
+        ".section .text\n",
+        "first: SET Ra, 1\n",
+        "    HLT\n",
+        ".section .data\n",
+        "    .word 1, 2\n",
+    );
+    const SECOND: &str = concat!(
+        ".section .data\n",
+        "    .word 3\n",
+        ".section .text\n",
+        "second: SET Rb, 2\n",
+        "    SET Rc, 3\n",
+        "    RET\n",
+    );
+
+    #[test]
+    fn sections_are_placed_in_order_of_appearance() {
+        let objects = [object("first.asm", FIRST), object("second.asm", SECOND)];
+
+        // Without a map everything follows on from address 0, grouped by name
+        let (_, map) = link(&objects, Endian::Big, None).unwrap();
+        assert_eq!(placements(&map), vec![
+            (".text", "first.asm", 0x0),
+            (".text", "second.asm", 0x8),
+            (".data", "first.asm", 0x14),
+            (".data", "second.asm", 0x1C),
+        ]);
+
+        // With one, each region fills up from its origin
+        let memory = MemoryMap::parse(concat!(
+            "MEMORY\n",
+            "    ROM : ORIGIN = 0x1000, LENGTH = 0x100\n",
+            "    RAM : ORIGIN = 0x4000, LENGTH = 0x100\n",
+            "SECTIONS\n",
+            "    .text > ROM\n",
+            "    .data > RAM\n",
+        )).unwrap();
+        let (_, map) = link(&objects, Endian::Big, Some(&memory)).unwrap();
+        assert_eq!(placements(&map), vec![
+            (".text", "first.asm", 0x1000),
+            (".text", "second.asm", 0x1008),
+            (".data", "first.asm", 0x4000),
+            (".data", "second.asm", 0x4008),
+        ]);
+    }
+
+    #[test]
+    fn region_overflow_is_reported() {
+        let objects = [object("first.asm", FIRST), object("second.asm", SECOND)];
+        let memory = MemoryMap::parse(concat!(
+            "MEMORY\n",
+            "    ROM : ORIGIN = 0x0, LENGTH = 0x10\n",
+            "SECTIONS\n",
+            "    .text > ROM\n",
+            "    .data > ROM\n",
+        )).unwrap();
+
+        let errors = link(&objects, Endian::Big, Some(&memory)).unwrap_err();
+        assert_eq!(errors, vec!["Region ROM overflows by 16 bytes (.text, .data)"]);
+    }
+
+    #[test]
+    fn unplaced_section_is_reported() {
+        let objects = [object("first.asm", FIRST)];
+        let memory = MemoryMap::parse("MEMORY\n    ROM : ORIGIN = 0x0, LENGTH = 0x100\nSECTIONS\n    .text > ROM\n").unwrap();
+
+        let errors = link(&objects, Endian::Big, Some(&memory)).unwrap_err();
+        assert_eq!(errors, vec!["Section .data isn't placed in any region of the memory map"]);
+    }
+
+    #[test]
+    fn only_needed_members_are_pulled() {
+        let main = object("main.asm", ".global main\n.extern square\nmain: CALL square\n    HLT\n");
+        let library = Archive::new(vec![
+            object("unused.asm", ".global unused\nunused: SET Ra, 0\n    RET\n"),
+            // Needs a member of its own, which comes after it
+            object("square.asm", ".global square\n.extern multiply\nsquare: SET Rb, Ra\n    JMP multiply\n"),
+            object("multiply.asm", ".global multiply\nmultiply: MUL Ra, Ra, Rb\n    RET\n"),
+        ]).unwrap();
+
+        let objects = pull_members(vec![main], &[library]);
+        let names: Vec<&str> = objects.iter().map(|object| object.name.as_str()).collect();
+        assert_eq!(names, vec!["main.asm", "square.asm", "multiply.asm"]);
+
+        let (program, _) = link(&objects, Endian::Big, None).unwrap();
+        let labels: Vec<&str> = program.labels.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(labels, vec!["MAIN", "SQUARE", "MULTIPLY"]);
+        // CALL and JMP take two words each, nothing of `unused` is in there
+        assert_eq!(program.segments[0].words.len(), 3 + 3 + 2);
+    }
+
+    #[test]
+    fn defined_symbols_pull_nothing() {
+        let main = object("main.asm", ".global main, square\nmain: CALL square\n    HLT\nsquare: MUL Ra, Ra, Ra\n    RET\n");
+        let library = Archive::new(vec![object("square.asm", ".global square\nsquare: MUL Ra, Ra, Ra\n    RET\n")]).unwrap();
+
+        let objects = pull_members(vec![main], &[library]);
+        assert_eq!(objects.len(), 1);
+    }
+
+    #[test]
+    fn double_words_keep_their_byte_order() {
+        let objects = [object("data.asm", ".dword 0x1122334455667788\n.word 0x99AABBCC\n")];
+
+        let (program, _) = link(&objects, Endian::Big, None).unwrap();
+        assert_eq!(program.to_bytes(), [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC]);
+
+        // Little endian puts the low word first, so the 8 bytes are fully reversed
+        let (program, _) = link(&objects, Endian::Little, None).unwrap();
+        assert_eq!(program.to_bytes(), [0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0xCC, 0xBB, 0xAA, 0x99]);
+    }
+}
@@ -0,0 +1,722 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{DirectiveKind, Expr, Instruction, Operand, Span, Statement};
+use crate::cfg::{is_branch, target, ControlFlowGraph, EdgeKind};
+use crate::instructions::Instructions;
+use crate::memmap::MemoryMap;
+use crate::token::{Token, TokenKind};
+use crate::utils::byte_to_register;
+
+// Lints
+//
+// `lint` looks for likely mistakes in a file that assembles fine. It works on
+// the control flow graph (see src/cfg.rs), so `.if`, `.while` and
+// pseudo-instructions are checked as the instructions they become.
+//
+// | ID                   | Reports                                                  |
+// | unreachable-code     | Code after JMP, RET or HLT that no label leads to        |
+// | unused-label         | Labels nothing refers to, `.global` and `.entry` count,  |
+// |                      | and so does starting the program                         |
+// | branch-without-cmp   | IF instructions with no CMP before them in their block,  |
+// |                      | or in the IF blocks that fall through to it              |
+// | read-before-write    | Registers read where no path has written them yet        |
+// | unused-write         | Values written to a register that nothing reads          |
+// | call-without-ret     | CALLs to a label that never reaches a RET                |
+// | store-outside-memory | SD, SD16 and SD8 to an address that isn't in any region  |
+// |                      | of the --memory-map, when every path to the store SETs   |
+// |                      | the address register to it                               |
+//
+// Registers follow the calling convention in src/registers.rs: a label that's
+// called or `.global` starts with the arguments, FP, LR and SP set, and a CALL
+// reads the arguments and sets A0 and A1. At RET the results and the
+// callee-saved registers are in use, at HLT only the results. PSH saves a
+// register whatever it holds, so it doesn't count as reading it.
+//
+// Every lint warns by default. A comment changes that for its line or, with
+// `lint-file`, for the whole file:
+//
+//   JMP done        ; lint: allow(unreachable-code)
+//   ; lint-file: deny(unused-label, unused-write)
+//
+// A comment on a statement also covers the labels right before it, so
+// `spare: HLT ; lint: allow(unused-label)` keeps working once `fmt` has put the
+// label on a line of its own.
+//
+// Denied lints are errors and make `lint` fail.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnreachableCode,
+    UnusedLabel,
+    BranchWithoutCmp,
+    ReadBeforeWrite,
+    UnusedWrite,
+    CallWithoutRet,
+    StoreOutsideMemory,
+}
+
+const LINTS: [Lint; 7] = [
+    Lint::UnreachableCode,
+    Lint::UnusedLabel,
+    Lint::BranchWithoutCmp,
+    Lint::ReadBeforeWrite,
+    Lint::UnusedWrite,
+    Lint::CallWithoutRet,
+    Lint::StoreOutsideMemory,
+];
+
+impl Lint {
+    pub fn id(self) -> &'static str {
+        match self {
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::UnusedLabel => "unused-label",
+            Lint::BranchWithoutCmp => "branch-without-cmp",
+            Lint::ReadBeforeWrite => "read-before-write",
+            Lint::UnusedWrite => "unused-write",
+            Lint::CallWithoutRet => "call-without-ret",
+            Lint::StoreOutsideMemory => "store-outside-memory",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub level: Level,
+    pub span: Span,
+    pub message: String,
+}
+
+/// A set of registers, bit n for register n.
+type Registers = u16;
+
+const ALL: Registers = 0xFFFF;
+const RESULTS: Registers = 0b11;           // A0, A1
+const ARGUMENTS: Registers = 0b1111;       // A0 - A3
+const CALLEE_SAVED: Registers = 0x1F00;    // S0 - S4
+const FP: Registers = 1 << 13;
+const LR: Registers = 1 << 14;
+const SP: Registers = 1 << 15;
+
+fn bit(register: u32) -> Registers {
+    1 << register
+}
+
+/// Registers the instruction reads and writes.
+fn effects(instruction: &Instruction) -> (Registers, Registers) {
+    let mut reads = 0;
+    let mut writes = 0;
+    for operand in &instruction.operands {
+        match operand {
+            Operand::Register(register) | Operand::Indirect(register) | Operand::BaseOffset(register, _) => reads |= bit(register.0),
+            Operand::Immediate(_) => {},
+        }
+    }
+
+    let first = match instruction.operands.first() {
+        Some(Operand::Register(register)) => bit(register.0),
+        _ => 0,
+    };
+
+    match instruction.op_code {
+        // The destination comes first and isn't read
+        Instructions::SET | Instructions::MOV | Instructions::LD | Instructions::LD16 | Instructions::LD8
+            | Instructions::LD16S | Instructions::LD8S | Instructions::POP => {
+            writes = first;
+            if instruction.operands.iter().skip(1).all(|operand| !matches!(operand, Operand::Register(register) | Operand::Indirect(register) | Operand::BaseOffset(register, _) if bit(register.0) == first)) {
+                reads &= !first;
+            }
+        },
+        Instructions::NOT if instruction.operands.len() > 1 => {
+            writes = first;
+            if !matches!(instruction.operands[1], Operand::Register(register) if bit(register.0) == first) {
+                reads &= !first;
+            }
+        },
+        // `ADD Ra, Rb, Rc` writes Ra, `ADD Ra, 1` also reads it
+        Instructions::ADD | Instructions::SUB | Instructions::MUL | Instructions::DIV | Instructions::AND | Instructions::OR
+            | Instructions::XOR | Instructions::NOT | Instructions::SL | Instructions::SR | Instructions::MOD => {
+            writes = first;
+            let reads_first = instruction.operands.len() < 3 || instruction.operands.iter().skip(1)
+                .any(|operand| matches!(operand, Operand::Register(register) | Operand::Indirect(register) | Operand::BaseOffset(register, _) if bit(register.0) == first));
+            if !reads_first {
+                reads &= !first;
+            }
+        },
+        Instructions::PSH => reads = 0,
+        _ => {},
+    }
+
+    (reads, writes)
+}
+
+fn register_names(registers: Registers) -> String {
+    (0..16).filter(|register| registers & bit(*register) != 0).map(byte_to_register).collect::<Vec<_>>().join(", ")
+}
+
+/// A NOP is `MOV Ra, Ra`, which shouldn't count as using Ra.
+fn is_nop(instruction: &Instruction) -> bool {
+    instruction.expansion.as_deref() == Some("NOP")
+}
+
+/// Levels set with `; lint:` and `; lint-file:` comments.
+struct Pragmas {
+    file: HashMap<Lint, Level>,
+    lines: HashMap<(usize, Lint), Level>,
+}
+
+impl Pragmas {
+    /// Reads the lint comments, failing on one that can't be understood.
+    fn parse(tokens: &[Token]) -> Result<Self, String> {
+        let mut pragmas = Pragmas { file: HashMap::new(), lines: HashMap::new() };
+        for token in tokens {
+            let TokenKind::Comment(text) = &token.kind else {
+                continue;
+            };
+            let text = text.trim();
+            let (whole_file, mut rest) = if let Some(rest) = text.strip_prefix("lint-file:") {
+                (true, rest)
+            } else if let Some(rest) = text.strip_prefix("lint:") {
+                (false, rest)
+            } else {
+                continue;
+            };
+
+            // `allow(a, b) deny(c)`
+            while let Some((level, after)) = rest.trim_start().split_once('(') {
+                let level = match level.trim() {
+                    "allow" => Level::Allow,
+                    "warn" => Level::Warn,
+                    "deny" => Level::Deny,
+                    other => return Err(format!("{}: Unknown lint level: {}, expected allow, warn or deny", token.span, other)),
+                };
+                let Some((ids, after)) = after.split_once(')') else {
+                    return Err(format!("{}: Missing ')' in lint comment", token.span));
+                };
+                for id in ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+                    let Some(lint) = LINTS.iter().copied().find(|lint| lint.id() == id) else {
+                        return Err(format!("{}: Unknown lint: {}", token.span, id));
+                    };
+                    if whole_file {
+                        pragmas.file.insert(lint, level);
+                    } else {
+                        pragmas.lines.insert((token.span.line, lint), level);
+                    }
+                }
+                rest = after;
+            }
+            if !rest.trim().is_empty() {
+                return Err(format!("{}: Expected allow(...), warn(...) or deny(...) in lint comment", token.span));
+            }
+        }
+        Ok(pragmas)
+    }
+
+    /// The level on the first of `lines` that sets one, or the file's.
+    fn level(&self, lint: Lint, lines: &[usize]) -> Level {
+        lines.iter().find_map(|line| self.lines.get(&(*line, lint)))
+            .or_else(|| self.file.get(&lint))
+            .copied()
+            .unwrap_or(Level::Warn)
+    }
+}
+
+struct Linter<'a> {
+    graph: &'a ControlFlowGraph,
+    pragmas: Pragmas,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, lint: Lint, span: &Span, message: String) {
+        self.report_on(lint, span, &[span.line], message);
+    }
+
+    /// Reports at `span`, going by the lint comments on any of `lines`.
+    fn report_on(&mut self, lint: Lint, span: &Span, lines: &[usize], message: String) {
+        let level = self.pragmas.level(lint, lines);
+        if level != Level::Allow {
+            self.diagnostics.push(Diagnostic { lint, level, span: span.clone(), message });
+        }
+    }
+
+    fn unreachable_code(&mut self) {
+        let graph = self.graph;
+        for (index, block) in graph.blocks.iter().enumerate().skip(1) {
+            let before = graph.blocks[index - 1].last().op_code;
+            let reached = graph.entry == Some(index) || graph.predecessors(index).next().is_some();
+            // `.else` and `.endw` end the code before them with a JMP, even after a `.break`
+            let generated = matches!(block.instructions[0].expansion.as_deref(), Some(".else" | ".endw"));
+            if block.labels.is_empty() && !generated && !reached && matches!(before, Instructions::JMP | Instructions::RET | Instructions::HLT) {
+                self.report(Lint::UnreachableCode, &block.instructions[0].span, format!("Code after {} is never reached", String::from(before)));
+            }
+        }
+    }
+
+    fn unused_labels(&mut self, statements: &[Statement]) {
+        // The program starts at the entry block whether or not it's named
+        let mut used: HashSet<String> = self.graph.entry.map_or(Vec::new(), |entry| self.graph.blocks[entry].labels.clone()).into_iter().collect();
+        for statement in statements {
+            match statement {
+                Statement::Instruction(instruction) => {
+                    for operand in &instruction.operands {
+                        if let Operand::Immediate(Expr::Symbol(name)) | Operand::BaseOffset(_, Expr::Symbol(name)) = operand {
+                            used.insert(name.clone());
+                        }
+                    }
+                },
+                Statement::Directive(directive) => match &directive.kind {
+                    DirectiveKind::Word(values) => {
+                        for value in values {
+                            if let Expr::Symbol(name) = value {
+                                used.insert(name.clone());
+                            }
+                        }
+                    },
+                    DirectiveKind::Entry(name) => {
+                        used.insert(name.clone());
+                    },
+                    DirectiveKind::Global(names) => used.extend(names.iter().cloned()),
+                    _ => {},
+                },
+                Statement::Label(_) => {},
+            }
+        }
+
+        for (index, statement) in statements.iter().enumerate() {
+            let (name, span) = match statement {
+                Statement::Label(label) => (&label.name, &label.span),
+                Statement::Directive(directive) => match &directive.kind {
+                    DirectiveKind::Proc(name) => (name, &directive.span),
+                    _ => continue,
+                },
+                Statement::Instruction(_) => continue,
+            };
+            // Generated labels have a '#'
+            if !name.contains('#') && !used.contains(name) {
+                // `fmt` moves the statement after a label, and its comment, to the next line
+                let statement_line = statements[index..].iter().find_map(|statement| match statement {
+                    Statement::Instruction(instruction) => Some(instruction.span.line),
+                    Statement::Directive(directive) => Some(directive.span.line),
+                    Statement::Label(_) => None,
+                });
+                let lines: Vec<usize> = std::iter::once(span.line).chain(statement_line).collect();
+                self.report_on(Lint::UnusedLabel, span, &lines, format!("Label {} is never used", name));
+            }
+        }
+    }
+
+    fn branches_without_cmp(&mut self) {
+        let graph = self.graph;
+        let mut compared_at_end = vec![false; graph.blocks.len()];
+        for (index, block) in graph.blocks.iter().enumerate() {
+            // An IF that doesn't jump leaves the flags as they were
+            let mut predecessors = graph.predecessors(index);
+            let mut compared = match (predecessors.next(), predecessors.next()) {
+                (Some(edge), None) if edge.kind == EdgeKind::Fallthrough && is_branch(graph.blocks[edge.from].last().op_code) => compared_at_end[edge.from],
+                _ => false,
+            };
+
+            for instruction in &block.instructions {
+                if instruction.op_code == Instructions::CMP {
+                    compared = true;
+                } else if is_branch(instruction.op_code) && !compared {
+                    let message = format!("{} has no CMP before it in its block", String::from(instruction.op_code));
+                    self.report(Lint::BranchWithoutCmp, &instruction.span, message);
+                } else if instruction.op_code == Instructions::CALL {
+                    compared = false;
+                }
+            }
+            compared_at_end[index] = compared;
+        }
+    }
+
+    /// Blocks code starts running at, with the registers already set there.
+    fn roots(&self, statements: &[Statement]) -> Vec<(usize, Registers)> {
+        let graph = self.graph;
+        let mut roots: Vec<(usize, Registers)> = graph.entry.map(|entry| (entry, SP)).into_iter().collect();
+        let called = graph.edges.iter().filter(|edge| edge.kind == EdgeKind::Call).map(|edge| edge.to);
+        let globals = statements.iter().filter_map(|statement| match statement {
+            Statement::Directive(directive) => match &directive.kind {
+                DirectiveKind::Global(names) => Some(names),
+                _ => None,
+            },
+            _ => None,
+        }).flatten().filter_map(|name| graph.labels.get(name).copied());
+        for block in called.chain(globals) {
+            roots.push((block, ARGUMENTS | FP | LR | SP));
+        }
+        roots
+    }
+
+    fn reads_before_writes(&mut self, statements: &[Statement]) {
+        let graph = self.graph;
+        let mut written: Vec<Option<Registers>> = vec![None; graph.blocks.len()];
+        for (block, registers) in self.roots(statements) {
+            written[block] = Some(written[block].unwrap_or(0) | registers);
+        }
+
+        let transfer = |block: usize, mut set: Registers| {
+            for instruction in &graph.blocks[block].instructions {
+                set |= effects(instruction).1;
+                if instruction.op_code == Instructions::CALL {
+                    set |= RESULTS;
+                }
+            }
+            set
+        };
+
+        // Registers written on any path into each block
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for edge in &graph.edges {
+                if !matches!(edge.kind, EdgeKind::Fallthrough | EdgeKind::Taken) {
+                    continue;
+                }
+                let Some(before) = written[edge.from] else {
+                    continue;
+                };
+                let after = transfer(edge.from, before);
+                let merged = written[edge.to].unwrap_or(0) | after;
+                if written[edge.to] != Some(merged) {
+                    written[edge.to] = Some(merged);
+                    changed = true;
+                }
+            }
+        }
+
+        for (index, block) in graph.blocks.iter().enumerate() {
+            let Some(mut set) = written[index] else {
+                continue;
+            };
+            for instruction in &block.instructions {
+                let (reads, writes) = effects(instruction);
+                let unset = reads & !set;
+                if unset != 0 && !is_nop(instruction) {
+                    let message = format!("{} read before anything writes it", register_names(unset));
+                    self.report(Lint::ReadBeforeWrite, &instruction.span, message);
+                }
+                set |= unset | writes;
+                if instruction.op_code == Instructions::CALL {
+                    set |= RESULTS;
+                }
+            }
+        }
+    }
+
+    /// Registers in use after the block, going by how it ends.
+    fn live_at_end(&self, block: usize, live_in: &[Registers]) -> Registers {
+        let graph = self.graph;
+        let last = graph.blocks[block].last();
+        match last.op_code {
+            Instructions::RET => return RESULTS | CALLEE_SAVED | FP | LR | SP,
+            Instructions::HLT => return RESULTS,
+            _ => {},
+        }
+
+        // A jump the graph can't follow, or code running into data, could go anywhere
+        let unknown_target = matches!(last.op_code, Instructions::JMP) || is_branch(last.op_code);
+        if unknown_target && !graph.successors(block).any(|edge| edge.kind == EdgeKind::Taken) {
+            return ALL;
+        }
+        if !matches!(last.op_code, Instructions::JMP) && graph.fallthrough(block).is_none() {
+            return ALL;
+        }
+
+        graph.successors(block)
+            .filter(|edge| matches!(edge.kind, EdgeKind::Fallthrough | EdgeKind::Taken))
+            .fold(0, |live, edge| live | live_in[edge.to])
+    }
+
+    fn unused_writes(&mut self) {
+        let graph = self.graph;
+        let step = |instruction: &Instruction, live: Registers| {
+            if instruction.op_code == Instructions::CALL {
+                return live & !(RESULTS | LR) | ARGUMENTS | SP;
+            }
+            let (reads, writes) = effects(instruction);
+            live & !writes | reads
+        };
+
+        // Registers in use at the start of each block
+        let mut live_in = vec![0; graph.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in (0..graph.blocks.len()).rev() {
+                let live = graph.blocks[index].instructions.iter().rev()
+                    .fold(self.live_at_end(index, &live_in), |live, instruction| step(instruction, live));
+                if live != live_in[index] {
+                    live_in[index] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        for (index, block) in graph.blocks.iter().enumerate() {
+            let mut live = self.live_at_end(index, &live_in);
+            for instruction in block.instructions.iter().rev() {
+                let (_, writes) = effects(instruction);
+                if writes & !live != 0 && !is_nop(instruction) {
+                    let message = format!("Value written to {} is never read", register_names(writes & !live));
+                    self.report(Lint::UnusedWrite, &instruction.span, message);
+                }
+                live = step(instruction, live);
+            }
+        }
+    }
+
+    fn calls_without_ret(&mut self) {
+        let graph = self.graph;
+        for edge in graph.edges.iter().filter(|edge| edge.kind == EdgeKind::Call) {
+            if graph.returns_from(edge.to).is_empty() {
+                let call = graph.blocks[edge.from].last();
+                let message = format!("CALL {} never reaches a RET", target(call).unwrap_or_default());
+                self.report(Lint::CallWithoutRet, &call.span, message);
+            }
+        }
+    }
+
+    /// The value SET gave each register when each block starts. A value carries
+    /// along fallthrough and taken edges as long as every way into the block
+    /// agrees on it. Calls and returns forget everything.
+    fn known_values(&self) -> Vec<Known> {
+        let graph = self.graph;
+        // None until some way into the block has been seen
+        let mut entries: Vec<Option<Known>> = (0..graph.blocks.len())
+            .map(|index| (graph.entry == Some(index) || graph.predecessors(index).next().is_none()).then_some(UNKNOWN))
+            .collect();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (index, block) in graph.blocks.iter().enumerate() {
+                let Some(mut known) = entries[index] else { continue };
+                block.instructions.iter().for_each(|instruction| track_value(&mut known, instruction));
+
+                for edge in graph.successors(index) {
+                    let incoming = if matches!(edge.kind, EdgeKind::Fallthrough | EdgeKind::Taken) { known } else { UNKNOWN };
+                    let merged = match entries[edge.to] {
+                        Some(current) => std::array::from_fn(|register| current[register].filter(|value| incoming[register] == Some(*value))),
+                        None => incoming,
+                    };
+                    if entries[edge.to] != Some(merged) {
+                        entries[edge.to] = Some(merged);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        entries.into_iter().map(|known| known.unwrap_or(UNKNOWN)).collect()
+    }
+
+    fn stores_outside_memory(&mut self, memory_map: &MemoryMap) {
+        let graph = self.graph;
+        for (block, mut known) in graph.blocks.iter().zip(self.known_values()) {
+            for instruction in &block.instructions {
+                let address = match instruction.operands.first() {
+                    Some(Operand::Register(register)) => known[register.0 as usize],
+                    _ => None,
+                };
+                let is_store = matches!(instruction.op_code, Instructions::SD | Instructions::SD16 | Instructions::SD8);
+                if let (true, Some(address)) = (is_store, address) {
+                    if !memory_map.regions.iter().any(|region| address >= region.origin && (address as u64) < region.end()) {
+                        let message = format!("{} stores to {:#X}, which isn't in any memory region", String::from(instruction.op_code), address);
+                        self.report(Lint::StoreOutsideMemory, &instruction.span, message);
+                    }
+                }
+                track_value(&mut known, instruction);
+            }
+        }
+    }
+}
+
+/// Register values set by SET, None where the value isn't known.
+type Known = [Option<u32>; 16];
+
+const UNKNOWN: Known = [None; 16];
+
+/// Updates the known register values after the instruction.
+fn track_value(known: &mut Known, instruction: &Instruction) {
+    let (_, writes) = effects(instruction);
+    for register in (0..16).filter(|register| writes & bit(*register) != 0) {
+        known[register as usize] = None;
+    }
+    if instruction.op_code == Instructions::CALL {
+        *known = UNKNOWN;
+    }
+    if let (Instructions::SET, [Operand::Register(register), Operand::Immediate(Expr::Number(value))]) = (instruction.op_code, instruction.operands.as_slice()) {
+        known[register.0 as usize] = Some(*value);
+    }
+}
+
+/// Runs every lint over the statements of a file, after label scoping and
+/// control flow lowering. `tokens` are the file's tokens, for the lint comments.
+/// Stores are only checked against a memory map if there is one. Fails if a lint
+/// comment is malformed or names a lint that doesn't exist.
+pub fn lint(statements: &[Statement], tokens: &[Token], memory_map: Option<&MemoryMap>) -> Result<Vec<Diagnostic>, String> {
+    let graph = ControlFlowGraph::build(statements);
+    let mut linter = Linter { graph: &graph, pragmas: Pragmas::parse(tokens)?, diagnostics: Vec::new() };
+
+    linter.unreachable_code();
+    linter.unused_labels(statements);
+    linter.branches_without_cmp();
+    linter.reads_before_writes(statements);
+    linter.unused_writes();
+    linter.calls_without_ret();
+    if let Some(memory_map) = memory_map {
+        linter.stores_outside_memory(memory_map);
+    }
+
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.column));
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{control, formatter, labels, lexer::Lexer, parser};
+
+    fn lint_source(source: &str) -> Vec<Diagnostic> {
+        lint_with_memory(source, None)
+    }
+
+    fn lint_with_memory(source: &str, memory_map: Option<&MemoryMap>) -> Vec<Diagnostic> {
+        let tokens = Lexer::new("test.asm").tokenize(source);
+        let statements = control::lower_control_flow(&labels::scope_labels(&parser::parse(&tokens), false));
+        lint(&statements, &tokens, memory_map).unwrap()
+    }
+
+    /// Line and ID of every diagnostic.
+    fn found(source: &str) -> Vec<(usize, &'static str)> {
+        lint_source(source).iter().map(|diagnostic| (diagnostic.span.line, diagnostic.lint.id())).collect()
+    }
+
+    /// Lines the lint reports on.
+    fn lines(source: &str, lint: Lint) -> Vec<usize> {
+        lint_source(source).iter().filter(|diagnostic| diagnostic.lint == lint).map(|diagnostic| diagnostic.span.line).collect()
+    }
+
+    #[test]
+    fn unreachable_code() {
+        assert_eq!(found("main: HLT\n    SET Ra, 1\n    HLT\n"), vec![(2, "unreachable-code")]);
+        assert!(found("main: JMP done\ndone: HLT\n").is_empty());
+    }
+
+    #[test]
+    fn unused_label() {
+        assert_eq!(lines("main: HLT\nspare: HLT\n", Lint::UnusedLabel), vec![2]);
+        assert!(found("main: JMP done\ndone: HLT\n").is_empty());
+    }
+
+    #[test]
+    fn branch_without_cmp() {
+        assert_eq!(lines("main: IFE done\n    SET Ra, 1\ndone: HLT\n", Lint::BranchWithoutCmp), vec![1]);
+        assert!(found("main: SET Ra, 0\n    CMP Ra, 0\n    IFE done\n    SET Ra, 1\ndone: HLT\n").is_empty());
+    }
+
+    #[test]
+    fn read_before_write() {
+        // T0 isn't an argument, so nothing has set it at the start
+        assert_eq!(lines("main: ADD Ra, T0, 1\n    HLT\n", Lint::ReadBeforeWrite), vec![1]);
+        assert!(found("main: SET T0, 2\n    ADD Ra, T0, 1\n    HLT\n").is_empty());
+    }
+
+    #[test]
+    fn unused_write() {
+        assert_eq!(found("main: SET T0, 1\n    HLT\n"), vec![(1, "unused-write")]);
+        // A0 holds the result when the program halts
+        assert!(found("main: SET A0, 1\n    HLT\n").is_empty());
+    }
+
+    #[test]
+    fn call_without_ret() {
+        assert_eq!(lines("main: CALL work\n    HLT\nwork: HLT\n", Lint::CallWithoutRet), vec![1]);
+        assert!(found("main: CALL work\n    HLT\nwork: RET\n").is_empty());
+    }
+
+    #[test]
+    fn store_outside_memory() {
+        let memory = MemoryMap::parse("MEMORY\n    RAM : ORIGIN = 0x4000, LENGTH = 0x1000\n").unwrap();
+        let store = |address: &str| lint_with_memory(&format!("main: SET Rd, {}\n    SET Rb, 7\n    SD Rd, Rb\n    HLT\n", address), Some(&memory));
+        assert_eq!(store("0x5000").iter().map(|diagnostic| diagnostic.lint).collect::<Vec<_>>(), vec![Lint::StoreOutsideMemory]);
+        assert!(store("0x4FFC").is_empty());
+    }
+
+    #[test]
+    fn pragmas_set_the_level() {
+        let diagnostics = lint_source("; lint-file: deny(unused-label)\nmain: HLT\nspare: HLT\n");
+        assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.level).collect::<Vec<_>>(), vec![Level::Deny]);
+
+        let source = "; lint-file: deny(unused-label)\nmain: HLT\nspare: HLT ; lint: warn(unused-label)\n";
+        assert_eq!(lint_source(source)[0].level, Level::Warn);
+        assert!(lint_source("; lint-file: allow(unused-label)\nmain: HLT\nspare: HLT\n").is_empty());
+    }
+
+    #[test]
+    fn bad_pragmas_are_errors() {
+        let error = |source: &str| {
+            let tokens = Lexer::new("test.asm").tokenize(source);
+            let statements = control::lower_control_flow(&labels::scope_labels(&parser::parse(&tokens), false));
+            lint(&statements, &tokens, None).unwrap_err()
+        };
+        assert_eq!(error("main: HLT ; lint: wan(unused-label)\n"), "test.asm:1:11: Unknown lint level: wan, expected allow, warn or deny");
+        assert_eq!(error("main: HLT ; lint: allow(unused-lable)\n"), "test.asm:1:11: Unknown lint: unused-lable");
+        assert_eq!(error("; lint-file: deny(unused-label\nmain: HLT\n"), "test.asm:1:1: Missing ')' in lint comment");
+        assert_eq!(error("main: HLT ; lint: allow(unused-label) please\n"), "test.asm:1:11: Expected allow(...), warn(...) or deny(...) in lint comment");
+    }
+
+    #[test]
+    fn label_pragma_survives_formatting() {
+        let source = "main: HLT\nspare: HLT ; lint: allow(unused-label, unreachable-code)\n";
+        assert!(lint_source(source).is_empty());
+        assert!(lint_source(&formatter::format("test.asm", source)).is_empty());
+        assert_eq!(lint_source("main: HLT\nspare: HLT\n")[0].lint, Lint::UnusedLabel);
+    }
+
+    #[test]
+    fn store_address_carries_across_blocks() {
+        // main.asm SETs Rd in its first block and stores through it two jumps later
+        let source = include_str!("../main.asm");
+        let rom_only = MemoryMap::parse("MEMORY\n    ROM : ORIGIN = 0, LENGTH = 0x1000\n").unwrap();
+        let stores: Vec<(usize, String)> = lint_with_memory(source, Some(&rom_only)).into_iter()
+            .filter(|diagnostic| diagnostic.lint == Lint::StoreOutsideMemory)
+            .map(|diagnostic| (diagnostic.span.line, diagnostic.message))
+            .collect();
+        assert_eq!(stores, vec![
+            (20, String::from("SD stores to 0x4000, which isn't in any memory region")),
+            (26, String::from("SD stores to 0x4000, which isn't in any memory region")),
+        ]);
+
+        let with_ram = MemoryMap::parse("MEMORY\n    ROM : ORIGIN = 0, LENGTH = 0x1000\n    RAM : ORIGIN = 0x4000, LENGTH = 0x1000\n").unwrap();
+        assert!(lint_with_memory(source, Some(&with_ram)).iter().all(|diagnostic| diagnostic.lint != Lint::StoreOutsideMemory));
+    }
+
+    #[test]
+    fn store_address_must_agree_on_every_path() {
+        let memory = MemoryMap::parse("MEMORY\n    RAM : ORIGIN = 0x4000, LENGTH = 0x1000\n").unwrap();
+        let source = concat!(
+            "main: SET Rd, 0x100\n",
+            "    CMP Ra, 0\n",
+            "    IFE other\n",
+            "    SET Rd, 0x4000\n",
+            "other: SD Rd, Rb\n",
+            "    HLT\n",
+        );
+        assert!(lint_with_memory(source, Some(&memory)).iter().all(|diagnostic| diagnostic.lint != Lint::StoreOutsideMemory));
+
+        let source = source.replace("SET Rd, 0x4000", "SET Rd, 0x100");
+        let stores = lint_with_memory(&source, Some(&memory)).into_iter().filter(|diagnostic| diagnostic.lint == Lint::StoreOutsideMemory).count();
+        assert_eq!(stores, 1);
+    }
+}
@@ -1,52 +1,326 @@
-mod instructions;
-mod lexer;
-mod utils;
-mod parser;
-mod generator;
-mod token;
+use dbv_compiler::{
+    archive, assembler, cfg, control, debuginfo, disassembler, formatter, labels, linker, lint, mapfile, memmap, object,
+    output, parser, utils,
+};
 
-use std::{fs::{File, read_to_string}, io::Write, env};
+use std::{fs::{self, File, read_to_string}, io::Write, env, path::Path, process};
 
-use instructions::*;
-use generator::*;
-use lexer::*;
-use parser::*;
-use token::*;
+use dbv_compiler::lexer::*;
+use dbv_compiler::output::*;
+use dbv_compiler::program::*;
 
+/// Options shared by every command.
+struct Options {
+    endian: Endian,
+    addressing: Addressing,
+    format: Option<OutputFormat>,
+    output: OutputConfig,
+    output_path: Option<String>, // Set with -o, otherwise the last positional argument
+    object: bool,                // Assemble to a relocatable object instead of a program
+    memory_map: Option<memmap::MemoryMap>,
+    map_path: Option<String>,    // Where to write the symbol map
+    debug_path: Option<String>,  // Debug info to write when assembling, or to read when disassembling
+    case_sensitive: bool,        // Keep the case of symbol names instead of upper-casing them
+    check: bool,                 // Only report files that `fmt` would change
+    dot: bool,                   // Write the control flow graph as Graphviz DOT
+}
 
+fn parse_option<T: TryFrom<String>>(name: &str, value: Option<String>, expected: &str) -> T {
+    let value = value.unwrap_or_else(|| panic!("Missing value for {}", name));
+    T::try_from(value.clone()).unwrap_or_else(|_| panic!("Invalid value for {}: {} (expected {})", name, value, expected))
+}
 
 fn main() {
-    // Input params - first is the input file, second is the output file
-    let args: Vec<String> = env::args().collect();
+    // Usage:
+    //   [asm] <input> <output>     Assemble the input file
+    //   disasm <input>             Disassemble a binary, image, Intel HEX or S-record file
+    //   link <objects...> -o <output>
+    //                              Link object files into a program, archives only add
+    //                              the members that define symbols the program uses
+    //   ar <objects...> -o <archive>
+    //                              Bundle object files into an archive
+    //   fmt [--check] <inputs...>  Rewrite source files in the standard style (see src/formatter.rs),
+    //                              or with --check list the ones that aren't and fail
+    //   cfg <input> [--dot]        Print the control flow graph (see src/cfg.rs), as Graphviz DOT
+    //                              with --dot, to -o <output> if given
+    //   lint <inputs...>           Report likely mistakes (see src/lint.rs), stores are checked
+    //                              against --memory-map if given
+    // Options:
+    //   -o <output>                Output file
+    //   -c                         Assemble to a relocatable object file
+    //   --case-sensitive           Treat `loop` and `LOOP` as different symbols
+    //   --memory-map <file>        Memory regions and where each section goes (see src/memmap.rs)
+    //   --map <file>               Write a symbol map when linking, as JSON if the file ends in .json
+    //   --debug-info <file>        Write debug info (source lines and labels) for the program,
+    //                              or read it to annotate a disassembly
+    //   --endian big|little        Byte order of the output words (default: big)
+    //   --addressing byte|word     Whether addresses count bytes or 16-bit words (default: byte)
+    //   --format <format>          Output format: bin, image, ihex, srec, memh, mif, coe, c or rust
+    //                              (default: picked from the output file extension)
+    //   --depth <words>            Memory depth for memh, mif and coe (default: just fits the program)
+    //   --width 8|16|32            Memory word width in bits for memh, mif and coe (default: 32)
+    //   --name <name>              Array name for c and rust (default: the output file name)
+    let mut args: Vec<String> = Vec::new();
+    let mut options = Options {
+        endian: Endian::Big,
+        addressing: Addressing::Byte,
+        format: None,
+        output: OutputConfig::default(),
+        output_path: None,
+        object: false,
+        memory_map: None,
+        map_path: None,
+        debug_path: None,
+        case_sensitive: false,
+        check: false,
+        dot: false,
+    };
 
-    let input = read_to_string(&args[1]).expect("Failed to read file");
+    let mut raw_args = env::args().skip(1);
+    while let Some(arg) = raw_args.next() {
+        match arg.as_str() {
+            "--endian" => options.endian = parse_option("--endian", raw_args.next(), "big or little"),
+            "--addressing" => options.addressing = parse_option("--addressing", raw_args.next(), "byte or word"),
+            "--format" => options.format = Some(parse_option("--format", raw_args.next(), "bin, image, ihex, srec, memh, mif, coe, c or rust")),
+            "--depth" => {
+                let value = raw_args.next().expect("Missing value for --depth");
+                let depth = utils::string_to_u32(&value).unwrap_or_else(|| panic!("Invalid value for --depth: {}", value));
+                options.output.depth = Some(depth);
+            },
+            "--width" => {
+                let value = raw_args.next().expect("Missing value for --width");
+                options.output.width = utils::string_to_u32(&value).unwrap_or_else(|| panic!("Invalid value for --width: {}", value));
+            },
+            "--name" => options.output.name = Some(raw_args.next().expect("Missing value for --name")),
+            "-o" => options.output_path = Some(raw_args.next().expect("Missing value for -o")),
+            "-c" => options.object = true,
+            "--case-sensitive" => options.case_sensitive = true,
+            "--check" => options.check = true,
+            "--dot" => options.dot = true,
+            "--memory-map" => {
+                let path = raw_args.next().expect("Missing value for --memory-map");
+                let text = read_to_string(&path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err));
+                let map = memmap::MemoryMap::parse(&text).unwrap_or_else(|err| panic!("{}: {}", path, err));
+                options.memory_map = Some(map);
+            },
+            "--debug-info" => options.debug_path = Some(raw_args.next().expect("Missing value for --debug-info")),
+            "--map" => options.map_path = Some(raw_args.next().expect("Missing value for --map")),
+            _ => args.push(arg),
+        }
+    }
+
+    match args.first().map(String::as_str) {
+        Some("disasm") => disassemble(&args[1..], &options),
+        Some("asm") => assemble(&args[1..], &options),
+        Some("link") => link(&args[1..], &options),
+        Some("ar") => create_archive(&args[1..], &options),
+        Some("fmt") => format_files(&args[1..], &options),
+        Some("lint") => lint_files(&args[1..], &options),
+        Some("cfg") => control_flow_graph(&args[1..], &options),
+        _ => assemble(&args, &options),
+    }
+}
 
-    let mut lexer = Lexer::new();
-    let tokens = lexer.tokenize(input);
+fn assemble(args: &[String], options: &Options) {
+    let input = read_to_string(&args[0]).expect("Failed to read file");
+    let output_path = options.output_path.as_ref().unwrap_or_else(|| &args[1]);
+
+    let lexer = Lexer::new(&args[0]);
+    let tokens = lexer.tokenize(&input);
 
     println!("Tokens: {:?}", tokens);
 
-    let parser = parser::Parser::new(&lexer);
+    let statements = labels::scope_labels(&parser::parse(&tokens), options.case_sensitive);
+    let statements = control::lower_control_flow(&statements);
+    let assembly = assembler::Assembly::new(&args[0], &statements, options.addressing);
+    let object = object::Object::assemble(&assembly).unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    });
+    if options.object {
+        if options.map_path.is_some() || options.debug_path.is_some() {
+            panic!("--map and --debug-info need a linked program, they can't be used with -c");
+        }
+        fs::write(output_path, object.to_bytes()).expect("Failed to write object file");
+        return;
+    }
+
+    // A single file is linked on its own, which places its sections
+    link_objects(&[object], output_path, options);
+}
+
+fn link(args: &[String], options: &Options) {
+    let output_path = options.output_path.as_ref().expect("Missing output file, use -o <output>");
+
+    let mut objects = Vec::new();
+    let mut archives = Vec::new();
+    for path in args {
+        let bytes = fs::read(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err));
+        if archive::is_archive(&bytes) {
+            archives.push(archive::Archive::from_bytes(path, &bytes).unwrap_or_else(|err| panic!("{}", err)));
+        } else {
+            objects.push(object::Object::from_bytes(path, &bytes).unwrap_or_else(|err| panic!("{}", err)));
+        }
+    }
+
+    let objects = linker::pull_members(objects, &archives);
+    link_objects(&objects, output_path, options);
+}
+
+fn create_archive(args: &[String], options: &Options) {
+    let output_path = options.output_path.as_ref().expect("Missing output file, use -o <archive>");
+
+    let members: Vec<object::Object> = args.iter().map(|path| {
+        let bytes = fs::read(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err));
+        // Members are named after the file, the directory doesn't matter once bundled
+        let name = Path::new(path).file_name().map_or(path.clone(), |name| name.to_string_lossy().to_string());
+        object::Object::from_bytes(&name, &bytes).unwrap_or_else(|err| panic!("{}: {}", path, err))
+    }).collect();
+
+    let archive = archive::Archive::new(members).unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    });
+
+    println!("Archive: {} members, {} symbols", archive.members.len(), archive.index.len());
+    fs::write(output_path, archive.to_bytes()).expect("Failed to write archive");
+}
+
+fn format_files(args: &[String], options: &Options) {
+    if args.is_empty() {
+        panic!("Missing input file");
+    }
+
+    let mut unformatted = 0;
+    for path in args {
+        let input = read_to_string(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err));
+        let formatted = formatter::format(path, &input);
+        if formatted == input {
+            continue;
+        }
 
-    let mut nodes = Vec::new();
-    for token in tokens {
-        if let Some(node) = parser.parse_line(token) {
-            nodes.push(node);
+        if options.check {
+            eprintln!("{} isn't formatted", path);
+            unformatted += 1;
+        } else {
+            fs::write(path, formatted).unwrap_or_else(|err| panic!("Failed to write {}: {}", path, err));
+            println!("Formatted {}", path);
         }
     }
 
-    let gen_code = CodeGenerator::generate(nodes);
+    if unformatted > 0 {
+        eprintln!("error: {} {} formatting, run fmt without --check", unformatted, if unformatted == 1 { "file needs" } else { "files need" });
+        process::exit(1);
+    }
+}
+
+fn lint_files(args: &[String], options: &Options) {
+    if args.is_empty() {
+        panic!("Missing input file");
+    }
+
+    let mut warnings = 0;
+    let mut errors = 0;
+    for path in args {
+        let input = read_to_string(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err));
+        let tokens = Lexer::new(path).tokenize(&input);
+        let statements = labels::scope_labels(&parser::parse(&tokens), options.case_sensitive);
+        let statements = control::lower_control_flow(&statements);
+
+        let diagnostics = match lint::lint(&statements, &tokens, options.memory_map.as_ref()) {
+            Ok(diagnostics) => diagnostics,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                errors += 1;
+                continue;
+            },
+        };
+        for diagnostic in diagnostics {
+            let level = if diagnostic.level == lint::Level::Deny {
+                errors += 1;
+                "error"
+            } else {
+                warnings += 1;
+                "warning"
+            };
+            eprintln!("{}: {}[{}]: {}", diagnostic.span, level, diagnostic.lint.id(), diagnostic.message);
+        }
+    }
+
+    println!("{} warning{}, {} error{}", warnings, if warnings == 1 { "" } else { "s" }, errors, if errors == 1 { "" } else { "s" });
+    if errors > 0 {
+        process::exit(1);
+    }
+}
 
-    // Convert the vector of u32 to a vector of bytes
-    let gen_code: Vec<u8> = gen_code.iter().map(|x| {
-        let bytes = x.to_be_bytes();
-        bytes.to_vec()
-    }).flatten().collect();
+fn control_flow_graph(args: &[String], options: &Options) {
+    let path = args.first().expect("Missing input file");
+    let input = read_to_string(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err));
+    let tokens = Lexer::new(path).tokenize(&input);
+    let statements = labels::scope_labels(&parser::parse(&tokens), options.case_sensitive);
+    let graph = cfg::ControlFlowGraph::build(&control::lower_control_flow(&statements));
+
+    let text = if options.dot { graph.to_dot(path) } else { graph.to_text() };
+    match &options.output_path {
+        Some(output_path) => fs::write(output_path, text).unwrap_or_else(|err| panic!("Failed to write {}: {}", output_path, err)),
+        None => print!("{}", text),
+    }
+}
+
+fn link_objects(objects: &[object::Object], output_path: &str, options: &Options) {
+    match linker::link(objects, options.endian, options.memory_map.as_ref()) {
+        Ok((program, link_map)) => {
+            if let Some(map_path) = &options.map_path {
+                fs::write(map_path, mapfile::render(&link_map, map_path)).expect("Failed to write map file");
+            }
+            if let Some(debug_path) = &options.debug_path {
+                let debug = debuginfo::DebugInfo::from_map(&link_map);
+                fs::write(debug_path, debug.write()).expect("Failed to write debug info");
+            }
+            write_program(&program, output_path, options);
+        },
+        Err(errors) => {
+            for error in errors {
+                eprintln!("error: {}", error);
+            }
+            process::exit(1);
+        },
+    }
+}
+
+fn write_program(program: &Program, output_path: &str, options: &Options) {
+    // Convert the program to bytes in the requested format
+    let format = options.format.unwrap_or_else(|| OutputFormat::from_path(output_path));
+    // Name the arrays after the output file unless told otherwise
+    let mut config = options.output.clone();
+    if config.name.is_none() {
+        config.name = Path::new(output_path).file_stem().map(|stem| stem.to_string_lossy().to_string());
+    }
+
+    let gen_code = output::render(program, format, &config)
+        .unwrap_or_else(|err| panic!("Failed to write {:?}: {}", format, err));
 
     println!("Generated code: {:?}", gen_code);
+    println!("Image size: {} addresses ({:?} addressed, {:?} endian)", program.size(), program.addressing, program.endian);
 
-    // Now to finalize the compilation, save it as binary to a file
-    let mut file = File::create(&args[2]).unwrap();
+    // Now to finalize the compilation, save it to a file
+    let mut file = File::create(output_path).unwrap();
     file.write_all(&gen_code).unwrap();
 }
 
+fn disassemble(args: &[String], options: &Options) {
+    let bytes = fs::read(&args[0]).expect("Failed to read file");
+    let format = options.format.unwrap_or_else(|| OutputFormat::from_path(&args[0]));
+    let program = output::load(&bytes, format, options.endian, options.addressing)
+        .unwrap_or_else(|err| panic!("Failed to load {}: {}", args[0], err));
+
+    let debug = options.debug_path.as_ref().map(|path| {
+        let text = read_to_string(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err));
+        let mut debug = debuginfo::DebugInfo::parse(&text).unwrap_or_else(|err| panic!("{}: {}", path, err));
+        debug.load_sources();
+        debug
+    });
+
+    println!("; Entry point: {:#X}", program.entry);
+    print!("{}", disassembler::disassemble(&program, debug.as_ref()));
+}
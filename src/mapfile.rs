@@ -0,0 +1,313 @@
+// Symbol map files
+//
+// Written by the linker with `--map <file>`. Lists where every section ended up,
+// how full each memory region is and every symbol with its address, size and the
+// line that defines it. Addresses and sizes are in address units. A symbol's size
+// is the distance to the next symbol in its section, or to the end of the section.
+// Procedures (`.proc`) have their own size instead, and are listed again with the
+// symbols inside them. Labels the assembler made up (anonymous, numeric and the
+// ones `.if` and `.while` become) aren't symbols here, so they don't cut short
+// the size of the symbol before them.
+//
+// The text format is for reading, a `.json` file gets the same data as JSON for
+// scripts. Fields are only ever added to the JSON, never renamed or removed.
+
+#[derive(Debug, Clone)]
+pub struct MapSection {
+    pub name: String,
+    pub object: String, // Object the section came from
+    pub origin: u32,
+    pub size: u32,
+    pub bss: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MapSymbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub section: String,
+    pub file: String,
+    pub line: u32,
+    pub global: bool,
+    pub procedure: bool, // Defined by `.proc`, `size` runs to its `.endproc`
+}
+
+/// Source line of the instruction or data at an address.
+#[derive(Debug, Clone)]
+pub struct MapLine {
+    pub address: u32,
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    pub expansion: Option<String>, // Pseudo-instruction or directive the instruction came from
+}
+
+#[derive(Debug, Clone)]
+pub struct MapRegion {
+    pub name: String,
+    pub origin: u32,
+    pub length: u32,
+    pub used: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LinkMap {
+    pub sections: Vec<MapSection>, // Sorted by address
+    pub symbols: Vec<MapSymbol>,   // Sorted by address
+    pub regions: Vec<MapRegion>,   // Empty without a memory map
+    pub lines: Vec<MapLine>,       // Sorted by address, only written to the debug info
+}
+
+/// Writes the map in the format picked from the file extension.
+pub fn render(map: &LinkMap, path: &str) -> String {
+    if path.to_lowercase().ends_with(".json") {
+        write_json(map)
+    } else {
+        write_text(map)
+    }
+}
+
+fn percent(used: u32, total: u32) -> f64 {
+    if total == 0 { 0.0 } else { used as f64 * 100.0 / total as f64 }
+}
+
+fn symbol_lines(symbols: &[&MapSymbol]) -> String {
+    let name_width = symbols.iter().map(|symbol| symbol.name.len()).max().unwrap_or(0).max(6);
+    let section_width = symbols.iter().map(|symbol| symbol.section.len()).max().unwrap_or(0).max(7);
+
+    let mut output = format!("  {:<10}  {:<10}  {:<name_width$}  {:<section_width$}  Defined at\n", "Address", "Size", "Symbol", "Section");
+    for symbol in symbols {
+        let scope = if symbol.global { " (global)" } else { "" };
+        output += &format!(
+            "  0x{:08X}  0x{:08X}  {:<name_width$}  {:<section_width$}  {}:{}{}\n",
+            symbol.address, symbol.size, symbol.name, symbol.section, symbol.file, symbol.line, scope,
+        );
+    }
+
+    output
+}
+
+/// Writes the map as text.
+pub fn write_text(map: &LinkMap) -> String {
+    let mut output = String::from("# Generated by dbv_compiler, addresses and sizes are in address units\n");
+
+    if !map.regions.is_empty() {
+        output += "\nMemory regions\n";
+        output += &format!("  {:<10}  {:<10}  {:<10}  {:<10}  {:<10}  Usage\n", "Region", "Origin", "Length", "Used", "Free");
+        for region in &map.regions {
+            output += &format!(
+                "  {:<10}  0x{:08X}  0x{:08X}  0x{:08X}  0x{:08X}  {:.1}%\n",
+                region.name, region.origin, region.length, region.used,
+                region.length.saturating_sub(region.used), percent(region.used, region.length),
+            );
+        }
+    }
+
+    let name_width = map.sections.iter().map(|section| section.name.len()).max().unwrap_or(0).max(7);
+    output += "\nSections\n";
+    output += &format!("  {:<10}  {:<10}  {:<name_width$}  Object\n", "Address", "Size", "Section");
+    for section in &map.sections {
+        let bss = if section.bss { " (bss)" } else { "" };
+        output += &format!("  0x{:08X}  0x{:08X}  {:<name_width$}  {}{}\n", section.origin, section.size, section.name, section.object, bss);
+    }
+    let total: u32 = map.sections.iter().map(|section| section.size).sum();
+    output += &format!("  Total: 0x{:X} ({})\n", total, total);
+
+    let procedures: Vec<&MapSymbol> = map.symbols.iter().filter(|symbol| symbol.procedure).collect();
+    if !procedures.is_empty() {
+        output += "\nProcedures\n";
+        output += &symbol_lines(&procedures);
+        for procedure in procedures {
+            let inside: Vec<&MapSymbol> = map.symbols.iter()
+                .filter(|symbol| symbol.section == procedure.section && symbol.name != procedure.name)
+                .filter(|symbol| symbol.address >= procedure.address && symbol.address < procedure.address + procedure.size)
+                .collect();
+            if inside.is_empty() {
+                continue;
+            }
+
+            output += &format!("\n  {}\n", procedure.name);
+            for symbol in inside {
+                output += &format!("    0x{:08X}  {}\n", symbol.address, symbol.name);
+            }
+        }
+    }
+
+    let by_address: Vec<&MapSymbol> = map.symbols.iter().collect();
+    output += "\nSymbols by address\n";
+    output += &symbol_lines(&by_address);
+
+    let mut by_name = by_address;
+    by_name.sort_by(|a, b| a.name.cmp(&b.name).then(a.address.cmp(&b.address)));
+    output += "\nSymbols by name\n";
+    output += &symbol_lines(&by_name);
+
+    output
+}
+
+fn json_string(value: &str) -> String {
+    let mut output = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => output += "\\\"",
+            '\\' => output += "\\\\",
+            c if (c as u32) < 0x20 => output += &format!("\\u{:04x}", c as u32),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+fn json_array(items: Vec<String>) -> String {
+    if items.is_empty() {
+        return String::from("[]");
+    }
+
+    format!("[\n    {}\n  ]", items.join(",\n    "))
+}
+
+/// Writes the map as JSON.
+pub fn write_json(map: &LinkMap) -> String {
+    let regions = map.regions.iter().map(|region| format!(
+        "{{\"name\": {}, \"origin\": {}, \"length\": {}, \"used\": {}}}",
+        json_string(&region.name), region.origin, region.length, region.used,
+    )).collect();
+
+    let sections = map.sections.iter().map(|section| format!(
+        "{{\"name\": {}, \"object\": {}, \"address\": {}, \"size\": {}, \"bss\": {}}}",
+        json_string(&section.name), json_string(&section.object), section.origin, section.size, section.bss,
+    )).collect();
+
+    let symbols = map.symbols.iter().map(|symbol| format!(
+        "{{\"name\": {}, \"address\": {}, \"size\": {}, \"section\": {}, \"file\": {}, \"line\": {}, \"global\": {}, \"procedure\": {}}}",
+        json_string(&symbol.name), symbol.address, symbol.size, json_string(&symbol.section),
+        json_string(&symbol.file), symbol.line, symbol.global, symbol.procedure,
+    )).collect();
+
+    format!(
+        "{{\n  \"regions\": {},\n  \"sections\": {},\n  \"symbols\": {}\n}}\n",
+        json_array(regions), json_array(sections), json_array(symbols),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use crate::control::lower_control_flow;
+    use crate::labels::scope_labels;
+    use crate::lexer::Lexer;
+    use crate::linker::link;
+    use crate::memmap::MemoryMap;
+    use crate::object::Object;
+    use crate::parser::parse;
+    use crate::program::{Addressing, Endian};
+
+    const SOURCE: &str = concat!(
+        ".section .text\n",
+        ".global main\n",
+        "main: CALL func\n",
+        "    HLT\n",
+        "func: .if Ra == 0\n",
+        "    INC Ra\n",
+        ".endif\n",
+        "-:  RET\n",
+        ".section .data\n",
+        "table: .word 1, 2, 3\n",
+    );
+
+    const MEMORY: &str = concat!(
+        "MEMORY\n",
+        "    ROM : ORIGIN = 0x0000, LENGTH = 0x100\n",
+        "    RAM : ORIGIN = 0x4000, LENGTH = 0x100\n",
+        "SECTIONS\n",
+        "    .text > ROM\n",
+        "    .data > RAM\n",
+    );
+
+    fn link_map() -> LinkMap {
+        let statements = lower_control_flow(&scope_labels(&parse(&Lexer::new("prog.asm").tokenize(SOURCE)), false));
+        let object = Object::assemble(&Assembly::new("prog.asm", &statements, Addressing::Byte)).unwrap();
+        let memory = MemoryMap::parse(MEMORY).unwrap();
+        link(&[object], Endian::Big, Some(&memory)).unwrap().1
+    }
+
+    #[test]
+    fn text_map() {
+        // FUNC runs to the end of .text, past the labels of its `.if` and `-:`
+        assert_eq!(write_text(&link_map()), concat!(
+            "# Generated by dbv_compiler, addresses and sizes are in address units\n",
+            "\n",
+            "Memory regions\n",
+            "  Region      Origin      Length      Used        Free        Usage\n",
+            "  ROM         0x00000000  0x00000100  0x00000020  0x000000E0  12.5%\n",
+            "  RAM         0x00004000  0x00000100  0x0000000C  0x000000F4  4.7%\n",
+            "\n",
+            "Sections\n",
+            "  Address     Size        Section  Object\n",
+            "  0x00000000  0x00000020  .text    prog.asm\n",
+            "  0x00004000  0x0000000C  .data    prog.asm\n",
+            "  Total: 0x2C (44)\n",
+            "\n",
+            "Symbols by address\n",
+            "  Address     Size        Symbol  Section  Defined at\n",
+            "  0x00000000  0x0000000C  MAIN    .text    prog.asm:3 (global)\n",
+            "  0x0000000C  0x00000014  FUNC    .text    prog.asm:5\n",
+            "  0x00004000  0x0000000C  TABLE   .data    prog.asm:10\n",
+            "\n",
+            "Symbols by name\n",
+            "  Address     Size        Symbol  Section  Defined at\n",
+            "  0x0000000C  0x00000014  FUNC    .text    prog.asm:5\n",
+            "  0x00000000  0x0000000C  MAIN    .text    prog.asm:3 (global)\n",
+            "  0x00004000  0x0000000C  TABLE   .data    prog.asm:10\n",
+        ));
+    }
+
+    #[test]
+    fn json_map() {
+        assert_eq!(write_json(&link_map()), concat!(
+            "{\n",
+            "  \"regions\": [\n",
+            "    {\"name\": \"ROM\", \"origin\": 0, \"length\": 256, \"used\": 32},\n",
+            "    {\"name\": \"RAM\", \"origin\": 16384, \"length\": 256, \"used\": 12}\n",
+            "  ],\n",
+            "  \"sections\": [\n",
+            "    {\"name\": \".text\", \"object\": \"prog.asm\", \"address\": 0, \"size\": 32, \"bss\": false},\n",
+            "    {\"name\": \".data\", \"object\": \"prog.asm\", \"address\": 16384, \"size\": 12, \"bss\": false}\n",
+            "  ],\n",
+            "  \"symbols\": [\n",
+            "    {\"name\": \"MAIN\", \"address\": 0, \"size\": 12, \"section\": \".text\", \"file\": \"prog.asm\", \"line\": 3, \"global\": true, \"procedure\": false},\n",
+            "    {\"name\": \"FUNC\", \"address\": 12, \"size\": 20, \"section\": \".text\", \"file\": \"prog.asm\", \"line\": 5, \"global\": false, \"procedure\": false},\n",
+            "    {\"name\": \"TABLE\", \"address\": 16384, \"size\": 12, \"section\": \".data\", \"file\": \"prog.asm\", \"line\": 10, \"global\": false, \"procedure\": false}\n",
+            "  ]\n",
+            "}\n",
+        ));
+    }
+
+    #[test]
+    fn procedures_list_their_symbols() {
+        let source = concat!(
+            ".proc outer\n",
+            "    SET Ra, 1\n",
+            "inner: .while Ra < 4\n",
+            "    INC Ra\n",
+            ".endw\n",
+            "    RET\n",
+            ".endproc\n",
+            "after: HLT\n",
+        );
+        let statements = lower_control_flow(&scope_labels(&parse(&Lexer::new("proc.asm").tokenize(source)), false));
+        let object = Object::assemble(&Assembly::new("proc.asm", &statements, Addressing::Byte)).unwrap();
+        let map = link(&[object], Endian::Big, None).unwrap().1;
+
+        let sizes: Vec<(&str, u32)> = map.symbols.iter().map(|symbol| (symbol.name.as_str(), symbol.size)).collect();
+        assert_eq!(sizes, vec![("OUTER", 0x20), ("OUTER::INNER", 0x1C), ("AFTER", 0x4)]);
+
+        let text = write_text(&map);
+        assert!(text.contains("\n  OUTER\n    0x00000004  OUTER::INNER\n"), "{}", text);
+        assert!(render(&map, "prog.JSON").starts_with("{\n"));
+        assert!(render(&map, "prog.map").starts_with("# Generated"));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::program::{Endian, Program};
+
+// Memory initialization files for FPGA block RAM
+//
+// The memory is `depth` words of `width` bits, starting at address 0. Every
+// format lists every word of the memory, padding with zeros after the program,
+// since synthesis tools reject files that don't cover the whole memory.
+//
+//   memh - Verilog $readmemh, one hex word per line
+//   mif  - Altera / Intel Memory Initialization File
+//   coe  - Xilinx coefficient file
+
+pub const WIDTHS: [u32; 3] = [8, 16, 32];
+
+/// Splits the program into memory words. Without a depth the memory is just big
+/// enough to hold the program.
+pub fn memory_words(program: &Program, depth: Option<u32>, width: u32) -> Result<Vec<u32>, String> {
+    if !WIDTHS.contains(&width) {
+        return Err(format!("Unsupported memory width {} (expected 8, 16 or 32)", width));
+    }
+
+    // The memory starts at address 0, not at the start of the program
+    let unit = program.addressing.unit_size();
+    let mut bytes = vec![0; (program.start() * unit) as usize];
+    bytes.extend(program.to_bytes());
+
+    let word_bytes = (width / 8) as usize;
+    let needed = bytes.len().div_ceil(word_bytes) as u32;
+    let depth = depth.unwrap_or(needed);
+    if needed > depth {
+        return Err(format!("Program needs {} words of {} bits, but the memory depth is {}", needed, width, depth));
+    }
+
+    bytes.resize(depth as usize * word_bytes, 0);
+
+    Ok(bytes.chunks(word_bytes).map(|chunk| {
+        let word = chunk.iter().fold(0u32, |word, byte| (word << 8) | *byte as u32);
+        match program.endian {
+            Endian::Big => word,
+            Endian::Little => word.swap_bytes() >> (32 - width),
+        }
+    }).collect())
+}
+
+fn hex_digits(width: u32) -> usize {
+    (width / 4) as usize
+}
+
+/// Writes a Verilog `$readmemh` file.
+pub fn write_memh(program: &Program, depth: Option<u32>, width: u32) -> Result<String, String> {
+    let words = memory_words(program, depth, width)?;
+    let digits = hex_digits(width);
+
+    let mut output = format!("// {} words of {} bits\n", words.len(), width);
+    for word in words {
+        output += &format!("{:0digits$X}\n", word, digits = digits);
+    }
+
+    Ok(output)
+}
+
+/// Writes an Altera / Intel MIF file.
+pub fn write_mif(program: &Program, depth: Option<u32>, width: u32) -> Result<String, String> {
+    let words = memory_words(program, depth, width)?;
+    let digits = hex_digits(width);
+    let address_digits = format!("{:X}", words.len().saturating_sub(1)).len();
+
+    let mut output = String::new();
+    output += &format!("WIDTH={};\n", width);
+    output += &format!("DEPTH={};\n", words.len());
+    output += "ADDRESS_RADIX=HEX;\n";
+    output += "DATA_RADIX=HEX;\n";
+    output += "CONTENT BEGIN\n";
+    for (address, word) in words.iter().enumerate() {
+        output += &format!("    {:0address_digits$X} : {:0digits$X};\n", address, word, address_digits = address_digits, digits = digits);
+    }
+    output += "END;\n";
+
+    Ok(output)
+}
+
+/// Writes a Xilinx COE file.
+pub fn write_coe(program: &Program, depth: Option<u32>, width: u32) -> Result<String, String> {
+    let words = memory_words(program, depth, width)?;
+    let digits = hex_digits(width);
+
+    let mut output = format!("; {} words of {} bits\n", words.len(), width);
+    output += "memory_initialization_radix=16;\n";
+    output += "memory_initialization_vector=\n";
+    let lines: Vec<String> = words.iter().map(|word| format!("{:0digits$X}", word, digits = digits)).collect();
+    output += &lines.join(",\n");
+    output += ";\n";
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::{Addressing, Segment};
+
+    fn program(endian: Endian) -> Program {
+        Program::new(vec![Segment::new(0x4, vec![0x1122_3344])], 0x4, endian, Addressing::Byte)
+    }
+
+    #[test]
+    fn words_split_by_width() {
+        let words = |endian, width| memory_words(&program(endian), None, width).unwrap();
+
+        // The memory starts at 0, so the program's first 4 bytes are zeros
+        assert_eq!(words(Endian::Big, 32), [0, 0x1122_3344]);
+        assert_eq!(words(Endian::Big, 16), [0, 0, 0x1122, 0x3344]);
+        assert_eq!(words(Endian::Big, 8), [0, 0, 0, 0, 0x11, 0x22, 0x33, 0x44]);
+
+        // Little endian memory words keep the value of the program's words
+        assert_eq!(words(Endian::Little, 32), [0, 0x1122_3344]);
+        assert_eq!(words(Endian::Little, 16), [0, 0, 0x3344, 0x1122]);
+    }
+
+    #[test]
+    fn depth_pads_and_limits() {
+        assert_eq!(memory_words(&program(Endian::Big), Some(4), 32).unwrap(), [0, 0x1122_3344, 0, 0]);
+
+        let err = memory_words(&program(Endian::Big), Some(3), 16).unwrap_err();
+        assert_eq!(err, "Program needs 4 words of 16 bits, but the memory depth is 3");
+
+        let err = memory_words(&program(Endian::Big), None, 12).unwrap_err();
+        assert_eq!(err, "Unsupported memory width 12 (expected 8, 16 or 32)");
+    }
+
+    #[test]
+    fn memh() {
+        assert_eq!(write_memh(&program(Endian::Big), Some(6), 16).unwrap(), "// 6 words of 16 bits\n0000\n0000\n1122\n3344\n0000\n0000\n");
+        assert_eq!(write_memh(&program(Endian::Big), None, 32).unwrap(), "// 2 words of 32 bits\n00000000\n11223344\n");
+    }
+
+    #[test]
+    fn mif() {
+        // 17 words need two digit addresses
+        assert_eq!(write_mif(&program(Endian::Big), Some(17), 8).unwrap(), concat!(
+            "WIDTH=8;\n",
+            "DEPTH=17;\n",
+            "ADDRESS_RADIX=HEX;\n",
+            "DATA_RADIX=HEX;\n",
+            "CONTENT BEGIN\n",
+            "    00 : 00;\n",
+            "    01 : 00;\n",
+            "    02 : 00;\n",
+            "    03 : 00;\n",
+            "    04 : 11;\n",
+            "    05 : 22;\n",
+            "    06 : 33;\n",
+            "    07 : 44;\n",
+            "    08 : 00;\n",
+            "    09 : 00;\n",
+            "    0A : 00;\n",
+            "    0B : 00;\n",
+            "    0C : 00;\n",
+            "    0D : 00;\n",
+            "    0E : 00;\n",
+            "    0F : 00;\n",
+            "    10 : 00;\n",
+            "END;\n",
+        ));
+    }
+
+    #[test]
+    fn coe() {
+        assert_eq!(write_coe(&program(Endian::Little), Some(3), 32).unwrap(), concat!(
+            "; 3 words of 32 bits\n",
+            "memory_initialization_radix=16;\n",
+            "memory_initialization_vector=\n",
+            "00000000,\n",
+            "11223344,\n",
+            "00000000;\n",
+        ));
+    }
+}
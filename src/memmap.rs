@@ -0,0 +1,139 @@
+use crate::utils::string_to_u32;
+
+// Memory map description
+//
+// Lists the memory regions of the target and which region each section goes in.
+// Origins and lengths are in address units. Sections placed in the same region
+// are laid out one after the other in the order they first appear.
+//
+//   # Comments start with '#'
+//   MEMORY
+//       ROM : ORIGIN = 0x0000, LENGTH = 0x4000
+//       RAM : ORIGIN = 0x4000, LENGTH = 0x1000
+//
+//   SECTIONS
+//       .text > ROM
+//       .data > RAM
+//       .bss  > RAM
+
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: String,
+    pub origin: u32,
+    pub length: u32,
+}
+
+impl Region {
+    /// First address after the region.
+    pub fn end(&self) -> u64 {
+        self.origin as u64 + self.length as u64
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+    pub placements: Vec<(String, String)>, // Section name, region name
+}
+
+impl MemoryMap {
+    /// Returns the region the section is placed in.
+    pub fn region_for(&self, section: &str) -> Option<&Region> {
+        let (_, region) = self.placements.iter().find(|(name, _)| name == section)?;
+        self.regions.iter().find(|candidate| &candidate.name == region)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        enum Block {
+            None,
+            Memory,
+            Sections,
+        }
+
+        let mut map = MemoryMap::default();
+        let mut block = Block::None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or("");
+
+            // Punctuation is only there for readability
+            let cleaned = line.replace([':', ',', '=', '>'], " ");
+            let words: Vec<&str> = cleaned.split_whitespace().collect();
+
+            match words.as_slice() {
+                [] => {},
+                [keyword] if keyword.eq_ignore_ascii_case("MEMORY") => block = Block::Memory,
+                [keyword] if keyword.eq_ignore_ascii_case("SECTIONS") => block = Block::Sections,
+                [name, origin_key, origin, length_key, length] if matches!(block, Block::Memory)
+                    && origin_key.eq_ignore_ascii_case("ORIGIN")
+                    && length_key.eq_ignore_ascii_case("LENGTH") =>
+                {
+                    let origin = string_to_u32(origin).ok_or(format!("Line {}: invalid origin {}", line_number, origin))?;
+                    let length = string_to_u32(length).ok_or(format!("Line {}: invalid length {}", line_number, length))?;
+                    if map.regions.iter().any(|region| region.name == *name) {
+                        return Err(format!("Line {}: region {} is defined twice", line_number, name));
+                    }
+
+                    map.regions.push(Region { name: name.to_string(), origin, length });
+                },
+                [section, region] if matches!(block, Block::Sections) => {
+                    // Section names are case insensitive, like in `.section`
+                    let section = section.to_lowercase();
+                    if !map.regions.iter().any(|candidate| candidate.name == *region) {
+                        return Err(format!("Line {}: unknown region {}", line_number, region));
+                    }
+                    if map.placements.iter().any(|(name, _)| *name == section) {
+                        return Err(format!("Line {}: section {} is placed twice", line_number, section));
+                    }
+
+                    map.placements.push((section, region.to_string()));
+                },
+                _ => return Err(format!("Line {}: can't understand '{}'", line_number, line.trim())),
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_and_placements() {
+        let map = MemoryMap::parse(concat!(
+            "# Comments start with '#'\n",
+            "MEMORY\n",
+            "    ROM : ORIGIN = 0x0000, LENGTH = 0x4000\n",
+            "    RAM : ORIGIN = 0x4000, LENGTH = 0x1000  # After ROM\n",
+            "\n",
+            "sections\n",
+            "    .TEXT > ROM\n",
+            "    .data > RAM\n",
+            "    .bss  > RAM\n",
+        )).unwrap();
+
+        let regions: Vec<(&str, u32, u32)> = map.regions.iter().map(|region| (region.name.as_str(), region.origin, region.length)).collect();
+        assert_eq!(regions, vec![("ROM", 0x0, 0x4000), ("RAM", 0x4000, 0x1000)]);
+        assert_eq!(map.region_for(".text").unwrap().name, "ROM");
+        assert_eq!(map.region_for(".bss").unwrap().end(), 0x5000);
+        assert!(map.region_for(".rodata").is_none());
+
+        // Placement order is the order the sections are laid out in
+        let sections: Vec<&str> = map.placements.iter().map(|(section, _)| section.as_str()).collect();
+        assert_eq!(sections, vec![".text", ".data", ".bss"]);
+    }
+
+    #[test]
+    fn mistakes_are_errors() {
+        let error = |text: &str| MemoryMap::parse(text).unwrap_err();
+
+        assert_eq!(error("MEMORY\n    ROM : ORIGIN = 0x0, LENGTH = 0x10\n    ROM : ORIGIN = 0x10, LENGTH = 0x10\n"), "Line 3: region ROM is defined twice");
+        assert_eq!(error("MEMORY\n    ROM : ORIGIN = zero, LENGTH = 0x10\n"), "Line 2: invalid origin zero");
+        assert_eq!(error("SECTIONS\n    .text > ROM\n"), "Line 2: unknown region ROM");
+        assert_eq!(error("MEMORY\n    ROM : ORIGIN = 0x0, LENGTH = 0x10\nSECTIONS\n    .text > ROM\n    .Text > ROM\n"), "Line 5: section .text is placed twice");
+        assert_eq!(error("    .text > ROM\n"), "Line 1: can't understand '.text > ROM'");
+    }
+}
@@ -0,0 +1,511 @@
+use crate::assembler::Assembly;
+use crate::generator::{CodeGenerator, ISA_VERSION};
+use crate::program::Addressing;
+
+// Relocatable object files
+//
+// An object holds named sections whose addresses start at 0 (unless `.org` gave
+// them a fixed address), the symbols defined in them, the symbols it imports and
+// a relocation for every extension word that holds a label address. The linker
+// places the sections and patches the words.
+//
+// Everything is big endian, strings are a u16 length followed by UTF-8 bytes.
+//
+// | Field          | Contents                                                                 |
+// | Magic          | "DBVO"                                                                   |
+// | Format version | u16                                                                      |
+// | ISA version    | u16                                                                      |
+// | Flags          | u16 (bit 1: word addressed)                                              |
+// | Source         | name of the assembled file                                               |
+// | Entry          | u16 flag, then the entry symbol if the flag is 1                         |
+// | Sections       | u32 count, then (name, u8 flags, u32 origin, u32 word count, words)      |
+// | Symbols        | u32 count, then (name, u32 section, u32 offset, u8 flags, u32 line,      |
+// |                | u32 size)                                                                |
+// | Externs        | u32 count, then names                                                    |
+// | Relocations    | u32 count, then (u32 section, u32 offset, symbol name)                   |
+// | Lines          | u32 count, then (u32 section, u32 offset, u32 line, u32 column,          |
+// |                | expansion)                                                               |
+// | Double words   | u32 count, then (u32 section, u32 offset)                                |
+//
+// Section flags are bit 0: fixed origin, bit 1: bss. Symbol flags are bit 0:
+// global, bit 1: procedure. Only procedures have a size, it's 0 for the others.
+// A line's expansion is the pseudo-instruction or directive its instruction was
+// expanded from, an empty string for everything else.
+//
+// 64-bit values from `.dword` and `.double` are stored high word first. The
+// linker swaps the two words when it writes a little endian program, so the
+// value's bytes end up in the right order either way.
+
+pub const MAGIC: &[u8; 4] = b"DBVO";
+pub const FORMAT_VERSION: u16 = 7;
+
+const FLAG_WORD_ADDRESSED: u16 = 1 << 1;
+
+const SECTION_FIXED_ORIGIN: u8 = 1 << 0;
+const SECTION_BSS: u8 = 1 << 1;
+
+const SYMBOL_GLOBAL: u8 = 1 << 0;
+const SYMBOL_PROCEDURE: u8 = 1 << 1;
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub origin: Option<u32>, // Fixed address set by `.org`
+    pub bss: bool,           // Only reserves space, the words are all 0 and aren't written out
+    pub words: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub section: u32,      // Index into the object's sections
+    pub offset: u32,       // Address relative to the start of the section
+    pub global: bool,      // Exported with `.global`
+    pub line: u32,         // Line of the source file that defines it
+    pub size: Option<u32>, // Procedures know their size, from `.proc` to `.endproc`
+}
+
+/// An extension word that needs the address of `symbol`.
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub section: u32,
+    pub offset: u32,
+    pub symbol: String,
+}
+
+/// Source line of the instruction or data directive at an offset, for debug info.
+#[derive(Debug, Clone)]
+pub struct LineEntry {
+    pub section: u32,
+    pub offset: u32,
+    pub line: u32,
+    pub column: u32,
+    pub expansion: Option<String>, // Pseudo-instruction or directive the instruction came from
+}
+
+/// A 64-bit value: two words, high word first.
+#[derive(Debug, Clone)]
+pub struct DoubleWord {
+    pub section: u32,
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub name: String,   // File the object came from, only used for messages
+    pub source: String, // Source file it was assembled from
+    pub addressing: Addressing,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub externs: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    pub lines: Vec<LineEntry>,
+    pub double_words: Vec<DoubleWord>,
+    pub entry: Option<String>,
+}
+
+impl Object {
+    /// Builds an object from an assembled file. Label operands were resolved
+    /// against section-relative addresses, so every one of them is a relocation.
+    /// Fails if a `.global` names a label the file doesn't define.
+    pub fn assemble(assembly: &Assembly) -> Result<Self, String> {
+        let relocations = assembly.relocations.iter().map(|relocation| Relocation {
+            section: relocation.section as u32,
+            offset: relocation.offset,
+            symbol: relocation.symbol.clone(),
+        }).collect();
+
+        let mut lines: Vec<LineEntry> = assembly.nodes.iter().map(|node| (node.section, node.address, &node.span, &node.expansion))
+            .chain(assembly.data.iter().map(|(section, address, span, _)| (*section, *address, span, &None)))
+            .map(|(section, offset, span, expansion)| LineEntry {
+                section: section as u32,
+                offset,
+                line: span.line as u32,
+                column: span.column as u32,
+                expansion: expansion.clone(),
+            })
+            .collect();
+        lines.sort_by_key(|entry| (entry.section, entry.offset));
+
+        let section_words = CodeGenerator::generate(assembly);
+        let sections = assembly.sections.iter().zip(section_words).map(|(section, words)| Section {
+            name: section.name.clone(),
+            origin: section.origin,
+            bss: section.is_bss(),
+            words,
+        }).collect();
+
+        for (global, span) in &assembly.globals {
+            if assembly.get_label_address(global).is_none() {
+                return Err(format!("{}: Global symbol {} is never defined", span, global));
+            }
+        }
+
+        let symbols = assembly.labels.iter().map(|(label, section, address, span)| Symbol {
+            name: label.clone(),
+            section: *section as u32,
+            offset: *address,
+            global: assembly.globals.iter().any(|(global, _)| global == label),
+            line: span.line as u32,
+            size: assembly.procedures.iter().find(|(name, _)| name == label).map(|(_, size)| *size),
+        }).collect();
+
+        Ok(Self {
+            name: assembly.file.clone(),
+            source: assembly.file.clone(),
+            addressing: assembly.addressing,
+            sections,
+            symbols,
+            externs: assembly.externs.clone(),
+            relocations,
+            lines,
+            double_words: assembly.double_words.iter().map(|(section, offset)| DoubleWord { section: *section as u32, offset: *offset }).collect(),
+            entry: assembly.entry.clone(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.bytes(MAGIC);
+        writer.u16(FORMAT_VERSION);
+        writer.u16(ISA_VERSION);
+        writer.u16(if self.addressing == Addressing::Word { FLAG_WORD_ADDRESSED } else { 0 });
+        writer.string(&self.source);
+
+        match &self.entry {
+            Some(entry) => {
+                writer.u16(1);
+                writer.string(entry);
+            },
+            None => writer.u16(0),
+        }
+
+        writer.u32(self.sections.len() as u32);
+        for section in &self.sections {
+            let mut flags = 0;
+            if section.origin.is_some() {
+                flags |= SECTION_FIXED_ORIGIN;
+            }
+            if section.bss {
+                flags |= SECTION_BSS;
+            }
+
+            writer.string(&section.name);
+            writer.bytes(&[flags]);
+            writer.u32(section.origin.unwrap_or(0));
+            writer.u32(section.words.len() as u32);
+            for word in &section.words {
+                writer.u32(*word);
+            }
+        }
+
+        writer.u32(self.symbols.len() as u32);
+        for symbol in &self.symbols {
+            writer.string(&symbol.name);
+            writer.u32(symbol.section);
+            writer.u32(symbol.offset);
+            let mut flags = 0;
+            if symbol.global {
+                flags |= SYMBOL_GLOBAL;
+            }
+            if symbol.size.is_some() {
+                flags |= SYMBOL_PROCEDURE;
+            }
+            writer.bytes(&[flags]);
+            writer.u32(symbol.line);
+            writer.u32(symbol.size.unwrap_or(0));
+        }
+
+        writer.u32(self.externs.len() as u32);
+        for name in &self.externs {
+            writer.string(name);
+        }
+
+        writer.u32(self.relocations.len() as u32);
+        for relocation in &self.relocations {
+            writer.u32(relocation.section);
+            writer.u32(relocation.offset);
+            writer.string(&relocation.symbol);
+        }
+
+        writer.u32(self.lines.len() as u32);
+        for entry in &self.lines {
+            writer.u32(entry.section);
+            writer.u32(entry.offset);
+            writer.u32(entry.line);
+            writer.u32(entry.column);
+            writer.string(entry.expansion.as_deref().unwrap_or(""));
+        }
+
+        writer.u32(self.double_words.len() as u32);
+        for double_word in &self.double_words {
+            writer.u32(double_word.section);
+            writer.u32(double_word.offset);
+        }
+
+        writer.output
+    }
+
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.bytes(4)? != MAGIC {
+            return Err(format!("{} is not an object file", name));
+        }
+
+        let format_version = reader.u16()?;
+        if format_version != FORMAT_VERSION {
+            return Err(format!("{}: unsupported object format version {}", name, format_version));
+        }
+
+        let isa_version = reader.u16()?;
+        if isa_version != ISA_VERSION {
+            return Err(format!("{}: built for ISA version {}, but this is version {}", name, isa_version, ISA_VERSION));
+        }
+
+        let addressing = if reader.u16()? & FLAG_WORD_ADDRESSED != 0 { Addressing::Word } else { Addressing::Byte };
+        let source = reader.string()?;
+        let entry = if reader.u16()? != 0 { Some(reader.string()?) } else { None };
+
+        let mut sections = Vec::new();
+        for _ in 0..reader.u32()? {
+            let section_name = reader.string()?;
+            let flags = reader.bytes(1)?[0];
+            let origin = reader.u32()?;
+            let count = reader.u32()?;
+            let words = (0..count).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+            sections.push(Section {
+                name: section_name,
+                origin: if flags & SECTION_FIXED_ORIGIN != 0 { Some(origin) } else { None },
+                bss: flags & SECTION_BSS != 0,
+                words,
+            });
+        }
+
+        let mut symbols = Vec::new();
+        for _ in 0..reader.u32()? {
+            let symbol_name = reader.string()?;
+            let section = reader.u32()?;
+            let offset = reader.u32()?;
+            let flags = reader.bytes(1)?[0];
+            let line = reader.u32()?;
+            let size = reader.u32()?;
+            symbols.push(Symbol {
+                name: symbol_name,
+                section,
+                offset,
+                global: flags & SYMBOL_GLOBAL != 0,
+                line,
+                size: if flags & SYMBOL_PROCEDURE != 0 { Some(size) } else { None },
+            });
+        }
+
+        let mut externs = Vec::new();
+        for _ in 0..reader.u32()? {
+            externs.push(reader.string()?);
+        }
+
+        let mut relocations = Vec::new();
+        for _ in 0..reader.u32()? {
+            relocations.push(Relocation {
+                section: reader.u32()?,
+                offset: reader.u32()?,
+                symbol: reader.string()?,
+            });
+        }
+
+        let mut lines = Vec::new();
+        for _ in 0..reader.u32()? {
+            lines.push(LineEntry {
+                section: reader.u32()?,
+                offset: reader.u32()?,
+                line: reader.u32()?,
+                column: reader.u32()?,
+                expansion: Some(reader.string()?).filter(|expansion| !expansion.is_empty()),
+            });
+        }
+
+        let mut double_words = Vec::new();
+        for _ in 0..reader.u32()? {
+            double_words.push(DoubleWord { section: reader.u32()?, offset: reader.u32()? });
+        }
+
+        let size = addressing.instruction_size();
+        for symbol in &symbols {
+            // A label can sit right after the last word of its section
+            match sections.get(symbol.section as usize) {
+                None => return Err(format!("{}: symbol {} is in a missing section", name, symbol.name)),
+                Some(section) if symbol.offset as u64 > section.words.len() as u64 * size as u64 => {
+                    return Err(format!("{}: symbol {} at {:#X} is outside its section", name, symbol.name, symbol.offset));
+                },
+                Some(_) => {},
+            }
+        }
+        for relocation in &relocations {
+            match sections.get(relocation.section as usize) {
+                None => return Err(format!("{}: relocation for {} is in a missing section", name, relocation.symbol)),
+                Some(section) if (relocation.offset / size) as usize >= section.words.len() => {
+                    return Err(format!("{}: relocation for {} at {:#X} is outside its section", name, relocation.symbol, relocation.offset));
+                },
+                Some(_) => {},
+            }
+        }
+        if lines.iter().any(|entry| entry.section as usize >= sections.len()) {
+            return Err(format!("{}: line table refers to a missing section", name));
+        }
+        for double_word in &double_words {
+            let index = (double_word.offset / size) as usize;
+            if sections.get(double_word.section as usize).is_none_or(|section| index + 1 >= section.words.len()) {
+                return Err(format!("{}: 64-bit value at {:#X} is outside its section", name, double_word.offset));
+            }
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            source,
+            addressing,
+            sections,
+            symbols,
+            externs,
+            relocations,
+            lines,
+            double_words,
+            entry,
+        })
+    }
+}
+
+/// Big endian writer for the object and archive formats.
+#[derive(Default)]
+pub(crate) struct Writer {
+    pub output: Vec<u8>,
+}
+
+impl Writer {
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.output.extend(bytes);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.output.extend(value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.output.extend(value.to_be_bytes());
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.u16(value.len() as u16);
+        self.bytes(value.as_bytes());
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.position + count > self.bytes.len() {
+            return Err(String::from("File is truncated"));
+        }
+
+        let bytes = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let length = self.u16()? as usize;
+        let bytes = self.bytes(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| String::from("File has an invalid string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::lower_control_flow;
+    use crate::labels::scope_labels;
+    use crate::lexer::Lexer;
+    use crate::parser::parse;
+
+    fn assemble(source: &str) -> Result<Object, String> {
+        let statements = lower_control_flow(&scope_labels(&parse(&Lexer::new("test.asm").tokenize(source)), false));
+        Object::assemble(&Assembly::new("test.asm", &statements, Addressing::Byte))
+    }
+
+    fn object(symbol_offset: u32, relocation_offset: u32) -> Object {
+        Object {
+            name: "test.o".to_string(),
+            source: "test.asm".to_string(),
+            addressing: Addressing::Byte,
+            sections: vec![Section { name: ".text".to_string(), origin: None, bss: false, words: vec![0, 0] }],
+            symbols: vec![Symbol { name: "end".to_string(), section: 0, offset: symbol_offset, global: true, line: 1, size: None }],
+            externs: vec!["target".to_string()],
+            relocations: vec![Relocation { section: 0, offset: relocation_offset, symbol: "target".to_string() }],
+            lines: Vec::new(),
+            double_words: Vec::new(),
+            entry: None,
+        }
+    }
+
+    #[test]
+    fn offsets_inside_sections_are_read() {
+        // A label may point just past the last word, a relocation has to patch one
+        let read = Object::from_bytes("test.o", &object(8, 4).to_bytes()).unwrap();
+        assert_eq!(read.symbols[0].offset, 8);
+        assert_eq!(read.relocations[0].offset, 4);
+    }
+
+    #[test]
+    fn symbol_outside_its_section_is_rejected() {
+        let err = Object::from_bytes("test.o", &object(12, 4).to_bytes()).unwrap_err();
+        assert_eq!(err, "test.o: symbol end at 0xC is outside its section");
+    }
+
+    #[test]
+    fn relocation_outside_its_section_is_rejected() {
+        let err = Object::from_bytes("test.o", &object(8, 8).to_bytes()).unwrap_err();
+        assert_eq!(err, "test.o: relocation for target at 0x8 is outside its section");
+    }
+
+    #[test]
+    fn undefined_global_is_an_error() {
+        let err = assemble("main: HLT\n.global main, missing\n").unwrap_err();
+        assert_eq!(err, "test.asm:2:1: Global symbol MISSING is never defined");
+
+        let object = assemble("main: HLT\n.global main\n").unwrap();
+        assert!(object.symbols.iter().any(|symbol| symbol.name == "MAIN" && symbol.global));
+    }
+
+    #[test]
+    fn procedures_know_their_size() {
+        let object = assemble(concat!(
+            "main: CALL outer\n",
+            "    HLT\n",
+            ".proc outer\n",
+            "    SET Ra, 1\n",
+            "    .proc inner\n",
+            "        ADD Ra, Ra, 1\n",
+            "        RET\n",
+            "    .endproc\n",
+            "    RET\n",
+            ".endproc\n",
+        )).unwrap();
+
+        let read = Object::from_bytes("test.o", &object.to_bytes()).unwrap();
+        let sizes: Vec<(&str, u32, Option<u32>)> = read.symbols.iter().map(|symbol| (symbol.name.as_str(), symbol.offset, symbol.size)).collect();
+        assert_eq!(sizes, vec![("MAIN", 0x0, None), ("OUTER", 0xC, Some(0x10)), ("OUTER::INNER", 0x10, Some(0x8))]);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::enum_conv_gen;
+use crate::embed;
+use crate::ihex;
+use crate::image;
+use crate::meminit;
+use crate::srec;
+use crate::program::{Endian, Addressing, Program};
+
+enum_conv_gen! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum OutputFormat {
+        Bin,   // Raw binary, gaps between segments are filled with zeros
+        Image, // Container with a header, segment table and entry point
+        Ihex, // Intel HEX
+        Srec, // Motorola S-record
+        Memh, // Verilog $readmemh
+        Mif,  // Altera / Intel memory initialization file
+        Coe,  // Xilinx coefficient file
+        C,    // C header with a uint32_t array
+        Rust, // Rust module with a [u32; N] static
+    }
+}
+
+/// Settings that only some formats use.
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    pub depth: Option<u32>,   // Memory depth in words for the memory initialization formats
+    pub width: u32,           // Memory word width in bits for the memory initialization formats
+    pub name: Option<String>, // Array name for the C and Rust formats
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            depth: None,
+            width: 32,
+            name: None,
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Picks the format from a file's extension, defaulting to raw binary.
+    pub fn from_path(path: &str) -> Self {
+        let extension = path.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+        match extension.as_deref() {
+            Some("dbvi") => OutputFormat::Image,
+            Some("hex") | Some("ihex") => OutputFormat::Ihex,
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => OutputFormat::Srec,
+            Some("memh") | Some("mem") => OutputFormat::Memh,
+            Some("mif") => OutputFormat::Mif,
+            Some("coe") => OutputFormat::Coe,
+            Some("h") => OutputFormat::C,
+            Some("rs") => OutputFormat::Rust,
+            _ => OutputFormat::Bin,
+        }
+    }
+}
+
+/// Renders the program in the given format.
+pub fn render(program: &Program, format: OutputFormat, config: &OutputConfig) -> Result<Vec<u8>, String> {
+    let output = match format {
+        OutputFormat::Bin => program.to_bytes(),
+        OutputFormat::Image => image::write(program),
+        OutputFormat::Ihex => ihex::write(program).into_bytes(),
+        OutputFormat::Srec => srec::write(program).into_bytes(),
+        OutputFormat::Memh => meminit::write_memh(program, config.depth, config.width)?.into_bytes(),
+        OutputFormat::Mif => meminit::write_mif(program, config.depth, config.width)?.into_bytes(),
+        OutputFormat::Coe => meminit::write_coe(program, config.depth, config.width)?.into_bytes(),
+        OutputFormat::C => embed::write_c(program, config.name.as_deref().unwrap_or("program"))?.into_bytes(),
+        OutputFormat::Rust => embed::write_rust(program, config.name.as_deref().unwrap_or("program"))?.into_bytes(),
+    };
+
+    Ok(output)
+}
+
+/// Loads a program written in any of the output formats that can be read back.
+pub fn load(bytes: &[u8], format: OutputFormat, endian: Endian, addressing: Addressing) -> Result<Program, String> {
+    match format {
+        // A container is recognised by its magic whatever the file is called
+        OutputFormat::Bin if image::is_image(bytes) => image::read(bytes),
+        OutputFormat::Bin => Program::from_bytes(bytes, endian, addressing),
+        OutputFormat::Image => image::read(bytes),
+        OutputFormat::Ihex => {
+            let text = String::from_utf8_lossy(bytes);
+            ihex::read(&text, endian, addressing)
+        },
+        OutputFormat::Srec => {
+            let text = String::from_utf8_lossy(bytes);
+            srec::read(&text, endian, addressing)
+        },
+        _ => Err(format!("{:?} files can't be loaded", format)),
+    }
+}
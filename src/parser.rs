@@ -1,74 +1,668 @@
-use crate::{token::Token, instructions::InstructionMode};
-use super::{Instructions, Lexer};
-    
-#[derive(Debug)]
-pub struct ASTNode {
-    pub op_code: Instructions,
-    pub mode: InstructionMode,
-    pub args: Vec<u32>,
-    pub token_repr: String,
+use std::collections::HashMap;
+
+use crate::ast::{Comparison, Condition, Directive, DirectiveKind, Expr, Instruction, Label, Operand, Reg, Span, Statement};
+use crate::pseudo;
+use crate::registers::register_number;
+use crate::token::{Token, TokenKind};
+use crate::utils::byte_to_register;
+use super::instructions::Instructions;
+
+/// Parses the lexer's tokens into statements. A line can hold a label followed
+/// by an instruction or directive, so it may give two statements.
+pub fn parse(tokens: &[Token]) -> Vec<Statement> {
+    let mut statements = Vec::new();
+    let mut aliases = HashMap::new(); // Register names added with `.req`, upper-cased
+    let mut block = None;             // `.struct` or `.enum` the lines are members of, and where it started
+
+    // Comments don't change what the code means
+    let code: Vec<&Token> = tokens.iter().filter(|token| !matches!(token.kind, TokenKind::Comment(_))).collect();
+    for line in code.split(|token| token.kind == TokenKind::Newline) {
+        LineParser { tokens: line, position: 0, aliases: &mut aliases, block: &mut block }.parse(&mut statements);
+    }
+
+    match block {
+        Some((Block::Struct, span)) => panic!("{}: .struct is never closed with .ends", span),
+        Some((Block::Enum, span)) => panic!("{}: .enum is never closed with .endenum", span),
+        None => {},
+    }
+
+    statements
 }
 
-impl ASTNode {
-    pub fn new(op_code: Instructions, mode: InstructionMode, args: Vec<u32>) -> Self {
-        let token_repr = String::from(op_code);
-        
-        Self {
-            op_code,
-            mode,
-            args,
-            token_repr,
-        }
+fn is_punctuation(token: Option<&&Token>, c: char) -> bool {
+    token.is_some_and(|token| token.kind == TokenKind::Punctuation(c))
+}
+
+/// Checks a number fits in the 32 bits an instruction or `.word` holds.
+fn narrow(value: u64, span: &Span) -> u32 {
+    match u32::try_from(value) {
+        Ok(value) => value,
+        Err(_) => panic!("{}: {:#X} doesn't fit in 32 bits, use .dword for 64-bit values", span, value),
     }
 }
 
-pub struct Parser<'a> {
-    lexer: &'a Lexer,
+/// The bits of a float as an IEEE-754 single.
+fn float_bits(value: f64, span: &Span) -> u32 {
+    let single = value as f32;
+    if value.is_finite() && single.is_infinite() {
+        panic!("{}: {} is too large for a 32-bit float", span, value);
+    }
+    single.to_bits()
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(lexer: &'a Lexer) -> Self {
-        Self { lexer }
+/// Span from the start of `first` to the end of `last`, on the same line.
+fn join(first: &Span, last: &Span) -> Span {
+    Span {
+        length: last.column + last.length - first.column,
+        ..first.clone()
+    }
+}
+
+/// Blocks whose lines are members instead of statements.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Block {
+    Struct,
+    Enum,
+}
+
+struct LineParser<'a> {
+    tokens: &'a [&'a Token],
+    position: usize,
+    aliases: &'a mut HashMap<String, Reg>,
+    block: &'a mut Option<(Block, Span)>,
+}
+
+impl<'a> LineParser<'a> {
+    /// Looks up a register by any of its names, see src/registers.rs.
+    fn register(&self, name: &str) -> Option<Reg> {
+        match self.aliases.get(&name.to_uppercase()) {
+            Some(register) => Some(*register),
+            None => register_number(name).map(Reg),
+        }
+    }
+
+    fn peek(&self) -> Option<&&'a Token> {
+        self.tokens.get(self.position)
     }
-    
-    pub fn parse_line(&self, tokens: Vec<Token>) -> Option<ASTNode> {
-        println!("{:?}", tokens);
 
-        // The opcode is always the first token
-        let op_code = match tokens[0] {
-            Token::OpCode(op_code) => op_code,
-            _ => panic!("Invalid token"),
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position).copied();
+        self.position += 1;
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    /// Span of the whole line from `start`, for error messages.
+    fn span_from(&self, start: usize) -> Span {
+        join(&self.tokens[start].span, &self.tokens[self.tokens.len() - 1].span)
+    }
+
+    /// Span of the tokens from `start` up to the current position.
+    fn span_since(&self, start: usize) -> Span {
+        join(&self.tokens[start].span, &self.tokens[self.position.max(start + 1) - 1].span)
+    }
+
+    fn text_from(&self, start: usize) -> String {
+        self.tokens[start..].iter().map(|token| describe(token)).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Skips the comma between operands. Commas are only there for readability.
+    fn skip_comma(&mut self) {
+        if is_punctuation(self.peek(), ',') {
+            self.position += 1;
+        }
+    }
+
+    fn parse(&mut self, statements: &mut Vec<Statement>) {
+        if let Some((block, _)) = *self.block {
+            if let Some(kind) = self.parse_member(block) {
+                statements.push(Statement::Directive(Directive { kind, span: self.span_from(0) }));
+            }
+            return;
+        }
+
+        // A label is a name, number, `+` or `-` followed by ':', it can be
+        // followed by a statement. See src/labels.rs for the kinds of label
+        if let (Some(token), true) = (self.peek(), is_punctuation(self.tokens.get(1), ':')) {
+            let name = match &token.kind {
+                TokenKind::Identifier(name) => name.clone(),
+                TokenKind::Number(value) => value.to_string(),
+                TokenKind::Punctuation(c @ ('+' | '-')) => c.to_string(),
+                _ => panic!("{}: Invalid label: {}", token.span, describe(token)),
+            };
+            statements.push(Statement::Label(Label { name, span: token.span.clone() }));
+            self.position += 2;
+        }
+
+        // `name .req Rb` is the one statement that doesn't start with its keyword
+        if let (Some(Token { kind: TokenKind::Identifier(alias), .. }), Some(Token { kind: TokenKind::Identifier(keyword), .. })) = (self.peek(), self.tokens.get(1)) {
+            if keyword.eq_ignore_ascii_case(".req") {
+                let start = self.position;
+                self.position += 2;
+                let kind = self.parse_req(alias, start);
+                statements.push(Statement::Directive(Directive { kind, span: self.span_since(start) }));
+                return;
+            }
+        }
+
+        let start = self.position;
+        let name = match self.next() {
+            None => return,
+            Some(Token { kind: TokenKind::Identifier(name), .. }) => name,
+            Some(token) => panic!("{}: Expected an instruction or directive, found {}", token.span, describe(token)),
         };
 
-        // The mode is always the second token
-        let mode = match tokens[1] {
-            Token::Mode(mode) => mode,
-            _ => panic!("Invalid token"),
+        if name.starts_with('.') {
+            let kind = self.parse_directive(name, start);
+            statements.push(Statement::Directive(Directive { kind, span: self.span_since(start) }));
+        } else {
+            let instructions = self.parse_instruction(name, start);
+            statements.extend(instructions.into_iter().map(Statement::Instruction));
+        }
+    }
+
+    /// Parses an instruction, pseudo-instructions give more than one.
+    fn parse_instruction(&mut self, name: &str, start: usize) -> Vec<Instruction> {
+
+        // Example instruction:
+        // ADD R1, R2, R3 -> Register mode
+        // ADD R1, R2, 0x00000001 -> Immediate mode
+        // ADD R1, R2, [R3] -> Register Indirect mode
+        // ADD R1, R2, [R3 + 0x00000001] -> Base Offset mode
+        //
+        // It's key to note the first parameter is always the destination register
+        // The second parameter is always the first source register. This can't
+        // be a value or memory address
+        // The third parameter is always the second source register, value or memory address
+        // Note - not every instruction has 3 parameters. Some have 2, some have 1
+        // However, each instruction's last parameter will always determine the mode
+        let mut operands = Vec::new();
+        let mut operand_spans = Vec::new();
+        while !self.at_end() {
+            let operand_start = self.position;
+            operands.push(self.parse_operand());
+            operand_spans.push(self.span_since(operand_start));
+            self.skip_comma();
+        }
+
+        let span = self.span_since(start);
+        if let Some(instructions) = pseudo::expand(name, &operands, &operand_spans, &span) {
+            return instructions;
+        }
+
+        // The first token in the line is the instruction. Check src/instructions.rs for more info
+        let op_code = match Instructions::try_from(name.to_string()) {
+            Ok(op_code) => op_code,
+            Err(_) => panic!("{}: Invalid instruction: {}", self.tokens[start].span, name),
         };
 
-        // The rest of the tokens are parameters. 
-        // If it's a label, we need to get the memory address
-        // from the lexer. Otherwise, we can pull the value
-
-        let mut args: Vec<u32> = Vec::new();
-        for token in &tokens[2..] {
-            match token {
-                Token::Label(label) => {
-                    let label = label.to_uppercase();
-                    let label_address = self.lexer.get_label_address(&label).unwrap();
-                    args.push(label_address);
-                },
-                Token::Register(register) => {
-                    args.push(*register);
+        if operands.len() > 3 {
+            panic!("{}: Too many operands: {}", self.span_from(start), self.text_from(start));
+        }
+
+        // Only the last operand can be something other than a register
+        for (operand, span) in operands.iter().zip(&operand_spans).rev().skip(1) {
+            if !matches!(operand, Operand::Register(_)) {
+                panic!("{}: Expected a register", span);
+            }
+        }
+
+        vec![Instruction { op_code, operands, span, expansion: None }]
+    }
+
+    fn parse_operand(&mut self) -> Operand {
+        if is_punctuation(self.peek(), '[') {
+            self.position += 1;
+            let base = self.parse_register();
+
+            let operand = if is_punctuation(self.peek(), '+') {
+                self.position += 1;
+                Operand::BaseOffset(base, self.parse_expr())
+            } else {
+                Operand::Indirect(base)
+            };
+
+            match self.next() {
+                Some(Token { kind: TokenKind::Punctuation(']'), .. }) => operand,
+                Some(token) => panic!("{}: Expected ']', found {}", token.span, describe(token)),
+                None => panic!("{}: Missing ']'", self.span_from(0)),
+            }
+        } else if let Some(Token { kind: TokenKind::Identifier(name), .. }) = self.peek() {
+            match self.register(name) {
+                Some(register) => {
+                    self.position += 1;
+                    Operand::Register(register)
                 },
-                Token::Value(value) => {
-                    args.push(*value);
+                None => Operand::Immediate(self.parse_expr()),
+            }
+        } else {
+            Operand::Immediate(self.parse_expr())
+        }
+    }
+
+    fn parse_register(&mut self) -> Reg {
+        match self.next() {
+            Some(Token { kind: TokenKind::Identifier(name), span }) => match self.register(name) {
+                Some(register) => register,
+                None => panic!("{}: Invalid register: {}", span, name),
+            },
+            Some(token) => panic!("{}: Expected a register, found {}", token.span, describe(token)),
+            None => panic!("{}: Expected a register", self.span_from(0)),
+        }
+    }
+
+    /// A number, `float(x)` or else the name of a label.
+    fn parse_expr(&mut self) -> Expr {
+        // A run of `+` or `-` on its own refers to an anonymous label, `-1` is a
+        // negative number stored as two's complement
+        if let Some(Token { kind: TokenKind::Punctuation(c @ ('+' | '-')), span }) = self.peek() {
+            let (c, span) = (*c, span.clone());
+            let mut name = String::new();
+            while is_punctuation(self.peek(), c) {
+                name.push(c);
+                self.position += 1;
+            }
+
+            if self.at_end() || is_punctuation(self.peek(), ',') || is_punctuation(self.peek(), ']') {
+                return Expr::Symbol(name);
+            }
+            return match self.next() {
+                Some(Token { kind: TokenKind::Number(value), span: number }) if name.len() == 1 => {
+                    let value = narrow(*value, number);
+                    Expr::Number(if c == '-' { value.wrapping_neg() } else { value })
                 },
-                _ => panic!("Invalid token"),
+                Some(token) => panic!("{}: Expected a value after {}, found {}", token.span, name, describe(token)),
+                None => panic!("{}: Expected a value", span),
+            };
+        }
+
+        // `float(1.5)` is the bit pattern of the float
+        if let Some(Token { kind: TokenKind::Identifier(name), span }) = self.peek() {
+            if name.eq_ignore_ascii_case("float") && is_punctuation(self.tokens.get(self.position + 1), '(') {
+                let span = span.clone();
+                self.position += 2;
+                let value = self.parse_float();
+                match self.next() {
+                    Some(Token { kind: TokenKind::Punctuation(')'), .. }) => {},
+                    Some(token) => panic!("{}: Expected ')', found {}", token.span, describe(token)),
+                    None => panic!("{}: Missing ')'", span),
+                }
+                return Expr::Number(float_bits(value, &span));
+            }
+        }
+
+        match self.next() {
+            Some(Token { kind: TokenKind::Number(value), span }) => Expr::Number(narrow(*value, span)),
+            Some(Token { kind: TokenKind::Identifier(name), .. }) => Expr::Symbol(name.clone()),
+            Some(token) => panic!("{}: Expected a value, found {}", token.span, describe(token)),
+            None => panic!("{}: Expected a value", self.span_from(0)),
+        }
+    }
+
+    /// Parses a line of a `.struct` or `.enum` block:
+    ///
+    ///   .struct Point      .enum Color
+    ///       x .word            RED
+    ///       y .word 2          GREEN = 5
+    ///       name .space 8      BLUE
+    ///   .ends              .endenum
+    ///
+    /// `.word` takes a number of words (1 if it's left out), `.space` a number of
+    /// bytes that is a multiple of 4.
+    fn parse_member(&mut self, block: Block) -> Option<DirectiveKind> {
+        let (name, span) = match self.next() {
+            None => return None,
+            Some(Token { kind: TokenKind::Identifier(name), span }) => (name, span),
+            Some(token) => panic!("{}: Expected a member name, found {}", token.span, describe(token)),
+        };
+
+        match (block, name.to_lowercase().as_str()) {
+            (Block::Struct, ".ends") | (Block::Enum, ".endenum") => {
+                if !self.at_end() {
+                    panic!("{}: {} doesn't take a value", self.span_from(0), name);
+                }
+                *self.block = None;
+                return Some(match block {
+                    Block::Struct => DirectiveKind::EndStruct(String::from("size")),
+                    Block::Enum => DirectiveKind::EndEnum,
+                });
+            },
+            (_, directive) if directive.starts_with('.') => {
+                panic!("{}: Only members can be written inside {}", span, if block == Block::Struct { ".struct" } else { ".enum" });
+            },
+            _ => {},
+        }
+
+        let number = |parser: &mut Self| match parser.next() {
+            Some(Token { kind: TokenKind::Number(value), span }) => narrow(*value, span),
+            Some(token) => panic!("{}: Expected a number, found {}", token.span, describe(token)),
+            None => panic!("{}: Expected a number", parser.span_from(0)),
+        };
+
+        let kind = match block {
+            Block::Struct => {
+                let words = match self.next() {
+                    Some(Token { kind: TokenKind::Identifier(keyword), .. }) if keyword.eq_ignore_ascii_case(".word") => {
+                        if self.at_end() { 1 } else { number(self) }
+                    },
+                    Some(Token { kind: TokenKind::Identifier(keyword), .. }) if keyword.eq_ignore_ascii_case(".space") => {
+                        let bytes = number(self);
+                        if !bytes.is_multiple_of(4) {
+                            panic!("{}: .space takes a size in bytes that is a multiple of 4", self.span_from(0));
+                        }
+                        bytes / 4
+                    },
+                    _ => panic!("{}: Expected .word or .space after {}", self.span_from(0), name),
+                };
+                DirectiveKind::Field(name.clone(), words)
+            },
+            Block::Enum => {
+                let value = if is_punctuation(self.peek(), '=') {
+                    self.position += 1;
+                    Some(number(self))
+                } else {
+                    None
+                };
+                DirectiveKind::EnumMember(name.clone(), value)
+            },
+        };
+
+        if !self.at_end() {
+            panic!("{}: Unexpected {} after {}", self.tokens[self.position].span, describe(self.tokens[self.position]), name);
+        }
+        Some(kind)
+    }
+
+    /// A float, which can also be written as an integer, with an optional '-'.
+    fn parse_float(&mut self) -> f64 {
+        let negative = is_punctuation(self.peek(), '-');
+        if negative {
+            self.position += 1;
+        }
+
+        let value = match self.next() {
+            Some(Token { kind: TokenKind::Float(value), .. }) => *value,
+            Some(Token { kind: TokenKind::Number(value), .. }) => *value as f64,
+            Some(token) => panic!("{}: Expected a number, found {}", token.span, describe(token)),
+            None => panic!("{}: Expected a number", self.span_from(0)),
+        };
+        if negative { -value } else { value }
+    }
+
+    /// A 64-bit integer with an optional '-', negative values are two's complement.
+    fn parse_dword(&mut self) -> u64 {
+        let negative = is_punctuation(self.peek(), '-');
+        if negative {
+            self.position += 1;
+        }
+
+        let value = match self.next() {
+            Some(Token { kind: TokenKind::Number(value), .. }) => *value,
+            Some(token) => panic!("{}: Expected a number, found {}", token.span, describe(token)),
+            None => panic!("{}: Expected a number", self.span_from(0)),
+        };
+        if negative { value.wrapping_neg() } else { value }
+    }
+
+    /// Parses the comma separated values of `.float`, `.double` and `.dword`.
+    fn parse_values<T>(&mut self, directive: &str, start: usize, parse: fn(&mut Self) -> T) -> Vec<T> {
+        let mut values = Vec::new();
+        while !self.at_end() {
+            values.push(parse(self));
+            self.skip_comma();
+        }
+
+        if values.is_empty() {
+            panic!("{}: Expected a value after {}", self.span_from(start), directive);
+        }
+        values
+    }
+
+    /// Handles `name .req register`, giving a register another name.
+    fn parse_req(&mut self, alias: &str, start: usize) -> DirectiveKind {
+        let span = self.span_from(start);
+        let register = self.parse_register();
+        if !self.at_end() {
+            panic!("{}: Expected a single register after .req", span);
+        }
+
+        if register_number(alias).is_some() {
+            panic!("{}: {} is already a register name", span, alias);
+        }
+        if let Some(existing) = self.aliases.get(&alias.to_uppercase()) {
+            panic!("{}: {} is already an alias for {}, use .unreq first", span, alias, byte_to_register(existing.0));
+        }
+
+        self.aliases.insert(alias.to_uppercase(), register);
+        DirectiveKind::Req(alias.to_string(), register)
+    }
+
+    /// Parses `Ra == Rb`, `Ra < 10` and so on, for `.if` and `.while`.
+    fn parse_condition(&mut self, directive: &str, start: usize) -> Condition {
+        let left = self.parse_register();
+
+        let mut operator = String::new();
+        while let Some(Token { kind: TokenKind::Punctuation(c @ ('=' | '!' | '<' | '>')), .. }) = self.peek() {
+            operator.push(*c);
+            self.position += 1;
+        }
+        let comparison = match operator.as_str() {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessOrEqual,
+            ">=" => Comparison::GreaterOrEqual,
+            "" => panic!("{}: Expected a comparison (==, !=, <, >, <= or >=) after {}", self.span_from(start), directive),
+            _ => panic!("{}: Invalid comparison: {}", self.span_from(start), operator),
+        };
+
+        let right = self.parse_operand();
+        if !self.at_end() {
+            panic!("{}: Unexpected {} after the condition", self.tokens[self.position].span, describe(self.tokens[self.position]));
+        }
+
+        Condition { left, comparison, right }
+    }
+
+    fn parse_directive(&mut self, name: &str, start: usize) -> DirectiveKind {
+        let directive = name.to_lowercase();
+        let span = self.span_from(start);
+
+        match directive.as_str() {
+            ".if" => return DirectiveKind::If(self.parse_condition(&directive, start)),
+            ".while" => return DirectiveKind::While(self.parse_condition(&directive, start)),
+            // Floats are written as the bits of an IEEE-754 single
+            ".float" => {
+                let values = self.parse_values(&directive, start, Self::parse_float);
+                return DirectiveKind::Word(values.into_iter().map(|value| Expr::Number(float_bits(value, &span))).collect());
+            },
+            ".double" => {
+                let values = self.parse_values(&directive, start, Self::parse_float);
+                return DirectiveKind::DWord(values.into_iter().map(f64::to_bits).collect());
+            },
+            ".dword" => return DirectiveKind::DWord(self.parse_values(&directive, start, Self::parse_dword)),
+            _ => {},
+        }
+
+        let mut arguments = Vec::new();
+        while !self.at_end() {
+            arguments.push(self.parse_expr());
+            self.skip_comma();
+        }
+
+        let single = || match arguments.as_slice() {
+            [argument] => argument.clone(),
+            _ => panic!("{}: Expected a single value after {}", span, directive),
+        };
+        let number = |argument: Expr| match argument {
+            Expr::Number(value) => value,
+            Expr::Symbol(name) => panic!("{}: Invalid {} value: {}", span, directive, name),
+        };
+        let symbol = |argument: Expr| match argument {
+            Expr::Symbol(name) => name,
+            Expr::Number(value) => panic!("{}: Expected a name after {}, found {}", span, directive, value),
+        };
+        let none = |kind: DirectiveKind| {
+            if !arguments.is_empty() {
+                panic!("{}: {} doesn't take a value", span, directive);
             }
+            kind
+        };
+        let names = || {
+            if arguments.is_empty() {
+                panic!("{}: Expected at least one symbol after {}", span, directive);
+            }
+            arguments.iter().cloned().map(symbol).collect()
+        };
+
+        match directive.as_str() {
+            ".org" => DirectiveKind::Org(number(single())),
+            ".section" => DirectiveKind::Section(symbol(single())),
+            ".word" => {
+                if arguments.is_empty() {
+                    panic!("{}: Expected a value after .word", span);
+                }
+                DirectiveKind::Word(arguments)
+            },
+            ".space" => {
+                let size = number(single());
+                if !size.is_multiple_of(4) {
+                    panic!("{}: .space takes a size in bytes that is a multiple of 4", span);
+                }
+                DirectiveKind::Space(size)
+            },
+            ".entry" => DirectiveKind::Entry(symbol(single())),
+            ".global" => DirectiveKind::Global(names()),
+            ".extern" => DirectiveKind::Extern(names()),
+            ".proc" => DirectiveKind::Proc(symbol(single())),
+            ".endproc" => none(DirectiveKind::EndProc),
+            ".struct" => {
+                *self.block = Some((Block::Struct, span.clone()));
+                DirectiveKind::Struct(symbol(single()))
+            },
+            ".enum" => {
+                *self.block = Some((Block::Enum, span.clone()));
+                DirectiveKind::Enum(symbol(single()))
+            },
+            ".else" => none(DirectiveKind::Else),
+            ".endif" => none(DirectiveKind::EndIf),
+            ".endw" => none(DirectiveKind::EndWhile),
+            ".break" => none(DirectiveKind::Break),
+            ".continue" => none(DirectiveKind::Continue),
+            ".unreq" => {
+                let alias = symbol(single());
+                if self.aliases.remove(&alias.to_uppercase()).is_none() {
+                    panic!("{}: {} isn't a register alias", span, alias);
+                }
+                DirectiveKind::Unreq(alias)
+            },
+            _ => panic!("{}: Unknown directive: {}", span, name),
         }
+    }
+}
 
-        // return
-        return Some(ASTNode::new(op_code, mode, args));
+/// How a token is shown in error messages.
+fn describe(token: &Token) -> String {
+    match &token.kind {
+        TokenKind::Identifier(name) => name.clone(),
+        TokenKind::Number(value) => format!("{:#X}", value),
+        TokenKind::Float(value) => value.to_string(),
+        TokenKind::String(text) => format!("{:?}", text),
+        TokenKind::Punctuation(c) => format!("'{}'", c),
+        TokenKind::Comment(_) => String::from("a comment"),
+        TokenKind::Newline => String::from("the end of the line"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse_source(source: &str) -> Vec<Statement> {
+        parse(&Lexer::new("test.asm").tokenize(source))
+    }
+
+    fn word_values(statement: &Statement) -> &[Expr] {
+        match statement {
+            Statement::Directive(Directive { kind: DirectiveKind::Word(values), .. }) => values,
+            other => panic!("Expected .word, found {:?}", other),
+        }
+    }
+
+    fn operands(statement: &Statement) -> &[Operand] {
+        match statement {
+            Statement::Instruction(instruction) => &instruction.operands,
+            other => panic!("Expected an instruction, found {:?}", other),
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn negative_word_is_twos_complement() {
+        let statements = parse_source("-: HLT\n.word -1, +2, -0x10\n");
+        assert_eq!(word_values(&statements[2]), [Expr::Number(0xFFFF_FFFF), Expr::Number(2), Expr::Number(0xFFFF_FFF0)]);
+    }
+
+    #[test]
+    fn lone_sign_is_anonymous_label() {
+        let statements = parse_source("-: JMP -\nJMP ++\n.word -, +\n");
+        assert_eq!(operands(&statements[1]), [Operand::Immediate(Expr::Symbol(String::from("-")))]);
+        assert_eq!(operands(&statements[2]), [Operand::Immediate(Expr::Symbol(String::from("++")))]);
+        assert_eq!(word_values(&statements[3]), [Expr::Symbol(String::from("-")), Expr::Symbol(String::from("+"))]);
+    }
+
+    #[test]
+    fn req_names_a_register_until_unreq() {
+        let statements = parse_source(concat!(
+            "counter .req Rc\n",
+            "SET COUNTER, sp\n",
+            ".unreq counter\n",
+            "counter .req r4\n",
+            "SET counter, 1\n",
+        ));
+        assert_eq!(operands(&statements[1]), [Operand::Register(Reg(2)), Operand::Register(Reg(15))]);
+        assert_eq!(operands(&statements[4])[0], Operand::Register(Reg(4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:2:1: counter is already an alias for Rc, use .unreq first")]
+    fn req_twice_panics() {
+        parse_source("counter .req Rc\ncounter .req Rd\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "test.asm:1:1: sp is already a register name")]
+    fn req_of_a_register_name_panics() {
+        parse_source("sp .req Rc\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "counter isn't a register alias")]
+    fn unreq_of_an_unknown_name_panics() {
+        parse_source("counter .req Rc\n.unreq counter\n.unreq counter\n");
+    }
+
+    #[test]
+    fn floats_are_stored_as_their_bits() {
+        let statements = parse_source(".float 1.5, -2, 0.1\nSET Ra, float(-0.5)\n.double 1, -2.5\n.dword 0x123456789ABCDEF0, -1\n");
+
+        assert_eq!(word_values(&statements[0]), [Expr::Number(0x3FC0_0000), Expr::Number(0xC000_0000), Expr::Number(0x3DCC_CCCD)]);
+        assert_eq!(operands(&statements[1])[1], Operand::Immediate(Expr::Number(0xBF00_0000)));
+        for (statement, expected) in statements[2..].iter().zip([[0x3FF0_0000_0000_0000, 0xC004_0000_0000_0000], [0x1234_5678_9ABC_DEF0, u64::MAX]]) {
+            match statement {
+                Statement::Directive(Directive { kind: DirectiveKind::DWord(values), .. }) => assert_eq!(values, &expected),
+                other => panic!("Expected a 64-bit value, found {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "is too large for a 32-bit float")]
+    fn float_out_of_range_panics() {
+        parse_source(".float 1000000000000000000000000000000000000000.0\n");
+    }
+}
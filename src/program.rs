@@ -0,0 +1,227 @@
+use crate::enum_conv_gen;
+
+enum_conv_gen! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Endian {
+        Big,
+        Little,
+    }
+}
+
+enum_conv_gen! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Addressing {
+        Byte, // Every address is one byte, an instruction word spans 4 addresses
+        Word, // Every address is a 16-bit word, an instruction word spans 2 addresses
+    }
+}
+
+impl Addressing {
+    /// Number of bytes covered by one address.
+    pub fn unit_size(&self) -> u32 {
+        match self {
+            Addressing::Byte => 1,
+            Addressing::Word => 2,
+        }
+    }
+
+    /// Number of addresses covered by one 32-bit instruction word.
+    pub fn instruction_size(&self) -> u32 {
+        4 / self.unit_size()
+    }
+}
+
+/// A run of contiguous words starting at `origin`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub origin: u32,
+    pub words: Vec<u32>,
+}
+
+impl Segment {
+    pub fn new(origin: u32, words: Vec<u32>) -> Self {
+        Self { origin, words }
+    }
+
+    /// First address after the segment.
+    pub fn end(&self, addressing: Addressing) -> u32 {
+        self.origin + self.words.len() as u32 * addressing.instruction_size()
+    }
+}
+
+/// An assembled program, along with the layout it should be written in.
+#[derive(Debug)]
+pub struct Program {
+    pub segments: Vec<Segment>,
+    pub entry: u32,
+    pub endian: Endian,
+    pub addressing: Addressing,
+    pub labels: Vec<(String, u32)>, // Label name, address of the labels the program exports
+}
+
+impl Program {
+    pub fn new(segments: Vec<Segment>, entry: u32, endian: Endian, addressing: Addressing) -> Self {
+        Self {
+            segments,
+            entry,
+            endian,
+            addressing,
+            labels: Vec::new(),
+        }
+    }
+
+    /// Loads a raw binary image, which always starts at address 0.
+    pub fn from_bytes(bytes: &[u8], endian: Endian, addressing: Addressing) -> Result<Self, String> {
+        let words = Self::bytes_to_words(bytes, endian)?;
+        Ok(Self::new(vec![Segment::new(0, words)], 0, endian, addressing))
+    }
+
+    /// Builds a program from runs of bytes read out of a text format. Without an
+    /// entry point the program starts at its lowest address.
+    pub fn from_chunks(chunks: Vec<(u32, Vec<u8>)>, entry: Option<u32>, endian: Endian, addressing: Addressing) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        for (origin, bytes) in chunks {
+            let words = Self::bytes_to_words(&bytes, endian)
+                .map_err(|err| format!("Data at {:#X}: {}", origin, err))?;
+            segments.push(Segment::new(origin, words));
+        }
+
+        let mut program = Self::new(segments, 0, endian, addressing);
+        program.entry = entry.unwrap_or(program.start());
+
+        Ok(program)
+    }
+
+    /// Converts bytes back to 32-bit words, the reverse of `word_to_bytes`.
+    pub fn bytes_to_words(bytes: &[u8], endian: Endian) -> Result<Vec<u32>, String> {
+        if !bytes.len().is_multiple_of(4) {
+            return Err(format!("Size {} is not a multiple of 4 bytes", bytes.len()));
+        }
+
+        Ok(bytes.chunks(4).map(|chunk| {
+            let chunk = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            match endian {
+                Endian::Big => u32::from_be_bytes(chunk),
+                Endian::Little => u32::from_le_bytes(chunk),
+            }
+        }).collect())
+    }
+
+    /// Lowest address used by the program.
+    pub fn start(&self) -> u32 {
+        self.segments.iter().map(|segment| segment.origin).min().unwrap_or(0)
+    }
+
+    /// First address after the highest segment.
+    pub fn end(&self) -> u32 {
+        self.segments.iter().map(|segment| segment.end(self.addressing)).max().unwrap_or(0)
+    }
+
+    /// Size of the program in addresses, including any gaps between segments.
+    pub fn size(&self) -> u32 {
+        self.end() - self.start()
+    }
+
+    /// Converts a single 32-bit word to bytes in the program's endianness.
+    ///
+    /// With word addressing the 32-bit word is two 16-bit words, most significant
+    /// first for big endian and least significant first for little endian. Each
+    /// 16-bit word is stored in the same byte order, so the bytes end up identical
+    /// to the byte addressed layout.
+    pub fn word_to_bytes(&self, word: u32) -> [u8; 4] {
+        match self.endian {
+            Endian::Big => word.to_be_bytes(),
+            Endian::Little => word.to_le_bytes(),
+        }
+    }
+
+    /// Returns the bytes of a single segment.
+    pub fn segment_bytes(&self, segment: &Segment) -> Vec<u8> {
+        segment.words.iter().flat_map(|word| self.word_to_bytes(*word)).collect()
+    }
+
+    /// Returns the whole program as 32-bit words, starting at the lowest address.
+    /// Gaps between segments are filled with zeros.
+    pub fn image_words(&self) -> Vec<u32> {
+        let mut bytes = self.to_bytes();
+        bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+        // The length is a multiple of 4, so this can't fail
+        Self::bytes_to_words(&bytes, self.endian).unwrap_or_default()
+    }
+
+    /// Returns the whole program as a flat stream of bytes, starting at the
+    /// lowest address. Gaps between segments are filled with zeros.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let unit = self.addressing.unit_size();
+        let mut bytes = vec![0; (self.size() * unit) as usize];
+        for segment in &self.segments {
+            let offset = ((segment.origin - self.start()) * unit) as usize;
+            let segment_bytes = self.segment_bytes(segment);
+            bytes[offset..offset + segment_bytes.len()].copy_from_slice(&segment_bytes);
+        }
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(endian: Endian, addressing: Addressing) -> Program {
+        let segments = vec![
+            Segment::new(0x4, vec![0x1122_3344]),
+            Segment::new(0x10, vec![0xAABB_CCDD]),
+        ];
+        Program::new(segments, 0x4, endian, addressing)
+    }
+
+    #[test]
+    fn endian_sets_byte_order() {
+        assert_eq!(program(Endian::Big, Addressing::Byte).word_to_bytes(0x1122_3344), [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(program(Endian::Little, Addressing::Byte).word_to_bytes(0x1122_3344), [0x44, 0x33, 0x22, 0x11]);
+
+        // Word addressing doesn't change the bytes, only how many addresses they take
+        assert_eq!(program(Endian::Big, Addressing::Word).word_to_bytes(0x1122_3344), [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(program(Endian::Little, Addressing::Word).word_to_bytes(0x1122_3344), [0x44, 0x33, 0x22, 0x11]);
+    }
+
+    #[test]
+    fn addressing_sets_sizes() {
+        assert_eq!((Addressing::Byte.unit_size(), Addressing::Byte.instruction_size()), (1, 4));
+        assert_eq!((Addressing::Word.unit_size(), Addressing::Word.instruction_size()), (2, 2));
+
+        let bytes = program(Endian::Big, Addressing::Byte);
+        assert_eq!((bytes.start(), bytes.end(), bytes.size()), (0x4, 0x14, 0x10));
+
+        let words = program(Endian::Big, Addressing::Word);
+        assert_eq!((words.start(), words.end(), words.size()), (0x4, 0x12, 0xE));
+    }
+
+    #[test]
+    fn gaps_are_filled_with_zeros() {
+        assert_eq!(program(Endian::Little, Addressing::Byte).to_bytes(), [
+            0x44, 0x33, 0x22, 0x11, 0, 0, 0, 0,
+            0, 0, 0, 0, 0xDD, 0xCC, 0xBB, 0xAA,
+        ]);
+
+        // The second segment is 12 addresses, 24 bytes, after the first
+        let words = program(Endian::Big, Addressing::Word);
+        let bytes = words.to_bytes();
+        assert_eq!(bytes.len(), 28);
+        assert_eq!(&bytes[24..], [0xAA, 0xBB, 0xCC, 0xDD]);
+        assert_eq!(words.image_words(), [0x1122_3344, 0, 0, 0, 0, 0, 0xAABB_CCDD]);
+    }
+
+    #[test]
+    fn from_bytes_reverses_to_bytes() {
+        for endian in [Endian::Big, Endian::Little] {
+            let bytes = program(endian, Addressing::Byte).to_bytes();
+            let read = Program::from_bytes(&bytes, endian, Addressing::Byte).unwrap();
+            assert_eq!(read.segments[0].words, [0x1122_3344, 0, 0, 0xAABB_CCDD]);
+        }
+
+        let err = Program::from_bytes(&[1, 2, 3], Endian::Big, Addressing::Byte).unwrap_err();
+        assert_eq!(err, "Size 3 is not a multiple of 4 bytes");
+    }
+}
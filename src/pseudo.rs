@@ -0,0 +1,191 @@
+use crate::ast::{Comparison, Expr, Instruction, Operand, Reg, Span};
+use crate::instructions::Instructions;
+
+// Pseudo-instructions
+//
+// Shorthands for common idioms. The parser expands them into real instructions,
+// so the layout pass sizes them like any other instruction. Every instruction of
+// an expansion keeps the span of the pseudo-instruction and its name, which the
+// listing shows next to it.
+//
+// | Pseudo           | Expands to                                          |
+// | NOP              | MOV Ra, Ra                                          |
+// | INC r            | ADD r, r, 1                                         |
+// | DEC r            | SUB r, r, 1                                         |
+// | NEG r            | NOT r, r / ADD r, r, 1                              |
+// | CLR r            | SET r, 0                                            |
+// | LI r, value      | SET r, value                                        |
+// | BEQ a, b, target | CMP a, b / IF target                                |
+// | BNE a, b, target | CMP a, b / IFN target                               |
+// | BLT a, b, target | CMP a, b / IFL target                               |
+// | BGT a, b, target | CMP a, b / IFG target                               |
+// | BGE a, b, target | CMP a, b / IFE target                               |
+// | BLE a, b, target | CMP a, b / IFNE target                              |
+// | PUSH r1, r2, ... | PSH r1 / PSH r2 / ...                               |
+// | POP r1, r2, ...  | ... / POP r2 / POP r1                               |
+//
+// `b` can be a register or a value. `POP` pops in reverse order, so the same
+// list given to `PUSH` and `POP` restores every register. `POP` with no operands
+// (discard the top of the stack) or a single one is the real instruction.
+
+const PSEUDO_INSTRUCTIONS: [&str; 14] = [
+    "NOP", "INC", "DEC", "NEG", "CLR", "LI",
+    "BEQ", "BNE", "BLT", "BGT", "BGE", "BLE",
+    "PUSH", "POP",
+];
+
+/// Returns true if the name is a pseudo-instruction.
+pub fn is_pseudo(name: &str) -> bool {
+    PSEUDO_INSTRUCTIONS.iter().any(|pseudo| pseudo.eq_ignore_ascii_case(name))
+}
+
+/// The comparison each branch makes.
+fn branch_comparison(name: &str) -> Option<Comparison> {
+    match name {
+        "BEQ" => Some(Comparison::Equal),
+        "BNE" => Some(Comparison::NotEqual),
+        "BLT" => Some(Comparison::Less),
+        "BGT" => Some(Comparison::Greater),
+        "BGE" => Some(Comparison::GreaterOrEqual),
+        "BLE" => Some(Comparison::LessOrEqual),
+        _ => None,
+    }
+}
+
+/// Expands a pseudo-instruction. Returns None if it's a real instruction, which
+/// is the case for `POP` with fewer than two operands.
+pub fn expand(name: &str, operands: &[Operand], operand_spans: &[Span], span: &Span) -> Option<Vec<Instruction>> {
+    let name = name.to_uppercase();
+    if !is_pseudo(&name) || (name == "POP" && operands.len() < 2) {
+        return None;
+    }
+
+    let count = |expected: usize| {
+        if operands.len() != expected {
+            panic!("{}: {} takes {} operand{}", span, name, expected, if expected == 1 { "" } else { "s" });
+        }
+    };
+    let register = |index: usize| match operands[index] {
+        Operand::Register(register) => register,
+        _ => panic!("{}: Expected a register", operand_spans[index]),
+    };
+    let instruction = |op_code: Instructions, operands: Vec<Operand>| Instruction {
+        op_code,
+        operands,
+        span: span.clone(),
+        expansion: Some(name.clone()),
+    };
+    let one = || Operand::Immediate(Expr::Number(1));
+
+    let expansion = match name.as_str() {
+        "NOP" => {
+            count(0);
+            vec![instruction(Instructions::MOV, vec![Operand::Register(Reg(0)), Operand::Register(Reg(0))])]
+        },
+        "INC" | "DEC" | "NEG" | "CLR" => {
+            count(1);
+            let r = Operand::Register(register(0));
+            match name.as_str() {
+                "INC" => vec![instruction(Instructions::ADD, vec![r.clone(), r, one()])],
+                "DEC" => vec![instruction(Instructions::SUB, vec![r.clone(), r, one()])],
+                "NEG" => vec![
+                    instruction(Instructions::NOT, vec![r.clone(), r.clone()]),
+                    instruction(Instructions::ADD, vec![r.clone(), r, one()]),
+                ],
+                _ => vec![instruction(Instructions::SET, vec![r, Operand::Immediate(Expr::Number(0))])],
+            }
+        },
+        "LI" => {
+            count(2);
+            if !matches!(operands[1], Operand::Immediate(_)) {
+                panic!("{}: Expected a value", operand_spans[1]);
+            }
+            vec![instruction(Instructions::SET, vec![Operand::Register(register(0)), operands[1].clone()])]
+        },
+        "PUSH" | "POP" => {
+            if operands.is_empty() {
+                panic!("{}: PUSH takes at least one register", span);
+            }
+            let registers: Vec<Reg> = (0..operands.len()).map(register).collect();
+            if name == "PUSH" {
+                registers.iter().map(|r| instruction(Instructions::PSH, vec![Operand::Register(*r)])).collect()
+            } else {
+                registers.iter().rev().map(|r| instruction(Instructions::POP, vec![Operand::Register(*r)])).collect()
+            }
+        },
+        _ => {
+            count(3);
+            let comparison = branch_comparison(&name).unwrap();
+            vec![
+                instruction(Instructions::CMP, vec![Operand::Register(register(0)), operands[1].clone()]),
+                instruction(comparison.jump(), vec![operands[2].clone()]),
+            ]
+        },
+    };
+
+    Some(expansion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Statement;
+    use crate::lexer::Lexer;
+    use crate::parser::parse;
+
+    /// The instructions a line parses to, and the pseudo-instruction each came from.
+    fn expansion(line: &str) -> Vec<(String, Option<String>)> {
+        parse(&Lexer::new("test.asm").tokenize(line)).iter()
+            .map(|statement| match statement {
+                Statement::Instruction(instruction) => (instruction.to_string(), instruction.expansion.clone()),
+                other => panic!("Expected an instruction, found {:?}", other),
+            })
+            .collect()
+    }
+
+    fn expands_to(line: &str, expected: &[&str]) {
+        let name = line.split_whitespace().next().unwrap().to_uppercase();
+        let found = expansion(line);
+        assert_eq!(found.iter().map(|(text, _)| text.as_str()).collect::<Vec<_>>(), expected, "{}", line);
+        assert!(found.iter().all(|(_, from)| from.as_deref() == Some(name.as_str())), "{}", line);
+    }
+
+    #[test]
+    fn arithmetic() {
+        expands_to("NOP", &["MOV Ra, Ra"]);
+        expands_to("INC Rb", &["ADD Rb, Rb, 0x1"]);
+        expands_to("dec Rc", &["SUB Rc, Rc, 0x1"]);
+        expands_to("NEG Rd", &["NOT Rd, Rd", "ADD Rd, Rd, 0x1"]);
+        expands_to("CLR Re", &["SET Re, 0x0"]);
+        expands_to("LI Rf, 0x1234", &["SET Rf, 0x1234"]);
+    }
+
+    #[test]
+    fn branches() {
+        expands_to("BEQ Ra, Rb, DONE", &["CMP Ra, Rb", "IF DONE"]);
+        expands_to("BNE Ra, 3, DONE", &["CMP Ra, 0x3", "IFN DONE"]);
+        expands_to("BLT Ra, Rb, DONE", &["CMP Ra, Rb", "IFL DONE"]);
+        expands_to("BGT Ra, Rb, DONE", &["CMP Ra, Rb", "IFG DONE"]);
+        expands_to("BGE Ra, Rb, DONE", &["CMP Ra, Rb", "IFE DONE"]);
+        expands_to("BLE Ra, Rb, DONE", &["CMP Ra, Rb", "IFNE DONE"]);
+    }
+
+    #[test]
+    fn push_and_pop_mirror_each_other() {
+        expands_to("PUSH Ra, Rb, Rc", &["PSH Ra", "PSH Rb", "PSH Rc"]);
+        expands_to("POP Ra, Rb, Rc", &["POP Rc", "POP Rb", "POP Ra"]);
+    }
+
+    #[test]
+    fn short_pop_is_real() {
+        assert_eq!(expansion("POP"), vec![(String::from("POP"), None)]);
+        assert_eq!(expansion("POP Ra"), vec![(String::from("POP Ra"), None)]);
+        assert!(expand("POP", &[], &[], &Span::default()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "INC takes 1 operand")]
+    fn wrong_operand_count_panics() {
+        expansion("INC Ra, Rb");
+    }
+}
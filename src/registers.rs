@@ -0,0 +1,71 @@
+use crate::utils::register_to_byte;
+
+// Register names and calling convention
+//
+// There are 16 registers. Each can be written as a letter (`Ra` - `Rp`), a
+// number (`R0` - `R15`) or its ABI name. Register names don't depend on case.
+//
+// | Register | Number | ABI name | Use                                  | Saved by |
+// | Ra - Rd  |  0 - 3 | A0 - A3  | Arguments, A0 and A1 hold the result | Caller   |
+// | Re - Rh  |  4 - 7 | T0 - T3  | Temporaries                          | Caller   |
+// | Ri - Rm  | 8 - 12 | S0 - S4  | Saved across calls                   | Callee   |
+// | Rn       |     13 | FP       | Frame pointer                        | Callee   |
+// | Ro       |     14 | LR       | Link register                        | Caller   |
+// | Rp       |     15 | SP       | Stack pointer                        | Callee   |
+//
+// Arguments past the fourth are pushed on the stack, last one first. `V0` and
+// `V1` are other names for A0 and A1 when they hold a result.
+//
+// Files can add their own names with `name .req Rb` and remove them again with
+// `.unreq name`.
+
+const ABI_NAMES: [(&str, u32); 18] = [
+    ("A0", 0), ("A1", 1), ("A2", 2), ("A3", 3),
+    ("V0", 0), ("V1", 1),
+    ("T0", 4), ("T1", 5), ("T2", 6), ("T3", 7),
+    ("S0", 8), ("S1", 9), ("S2", 10), ("S3", 11), ("S4", 12),
+    ("FP", 13), ("LR", 14), ("SP", 15),
+];
+
+/// Returns the number of a built-in register name: `Ra` - `Rp`, `R0` - `R15` or
+/// an ABI name.
+pub fn register_number(name: &str) -> Option<u32> {
+    let upper = name.to_uppercase();
+    if let Some((_, number)) = ABI_NAMES.iter().find(|(abi, _)| *abi == upper) {
+        return Some(*number);
+    }
+
+    let rest = upper.strip_prefix('R')?;
+    if rest.len() == 1 && rest.chars().all(|c| c.is_ascii_alphabetic()) {
+        return register_to_byte(rest);
+    }
+
+    // No leading zeros, so `R01` isn't a second spelling of `R1`
+    match rest.parse::<u32>() {
+        Ok(number) if number < 16 && rest == number.to_string() => Some(number),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_spelling_of_a_register() {
+        assert_eq!(register_number("Rb"), Some(1));
+        assert_eq!(register_number("rp"), Some(15));
+        assert_eq!(register_number("R0"), Some(0));
+        assert_eq!(register_number("r15"), Some(15));
+        assert_eq!(register_number("sp"), Some(15));
+        assert_eq!(register_number("V1"), Some(1));
+        assert_eq!(register_number("s4"), Some(12));
+    }
+
+    #[test]
+    fn near_misses_are_not_registers() {
+        for name in ["RR", "BR", "R01", "R16", "Rq", "R", "A4", "SPX"] {
+            assert_eq!(register_number(name), None, "{}", name);
+        }
+    }
+}
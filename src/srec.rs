@@ -0,0 +1,224 @@
+use crate::program::{Endian, Addressing, Program, Segment};
+use crate::utils::parse_hex;
+
+// Motorola S-record
+//
+// Every line is a record:
+// | S | Type | Count | Address      | Data          | Checksum |
+// | S |  T   |  CC   | AAAA[AA[AA]] | DD DD DD ...  |    SS    |
+//
+// Count is the number of bytes after it (address, data and checksum). The checksum
+// is the one's complement of the sum of the count, address and data bytes.
+// The address width depends on the type:
+//   S1 / S9 - 16-bit data / start address
+//   S2 / S8 - 24-bit data / start address
+//   S3 / S7 - 32-bit data / start address
+// S0 is a header and S5 / S6 hold the number of data records.
+// As with Intel HEX, addresses are in the program's address units.
+
+const BYTES_PER_RECORD: usize = 16;
+const HEADER: &[u8] = b"dbv";
+
+fn record(record_type: u8, address: u32, address_size: usize, data: &[u8]) -> String {
+    let mut bytes = vec![(address_size + data.len() + 1) as u8];
+    bytes.extend(&address.to_be_bytes()[4 - address_size..]);
+    bytes.extend(data);
+
+    let checksum = !bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(checksum);
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!("S{}{}\n", record_type, hex)
+}
+
+/// Writes the program as S-records, using the smallest address width that fits
+/// every address in the program.
+pub fn write(program: &Program) -> String {
+    let unit = program.addressing.unit_size();
+    let highest = program.end().saturating_sub(1).max(program.entry);
+    let (data_type, start_type, address_size) = if highest <= 0xFFFF {
+        (1, 9, 2)
+    } else if highest <= 0xFF_FFFF {
+        (2, 8, 3)
+    } else {
+        (3, 7, 4)
+    };
+
+    let mut output = record(0, 0, 2, HEADER);
+    let mut count = 0;
+
+    let mut segments: Vec<&Segment> = program.segments.iter().collect();
+    segments.sort_by_key(|segment| segment.origin);
+
+    for segment in segments {
+        let bytes = program.segment_bytes(segment);
+        for (i, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+            let address = segment.origin + (i * BYTES_PER_RECORD) as u32 / unit;
+            output += &record(data_type, address, address_size, chunk);
+            count += 1;
+        }
+    }
+
+    // S5 holds a 16-bit count, S6 a 24-bit one
+    if count <= 0xFFFF {
+        output += &record(5, count, 2, &[]);
+    } else {
+        output += &record(6, count, 3, &[]);
+    }
+    output += &record(start_type, program.entry, address_size, &[]);
+
+    output
+}
+
+/// Reads S-records back into a program.
+pub fn read(text: &str, endian: Endian, addressing: Addressing) -> Result<Program, String> {
+    let unit = addressing.unit_size();
+    let mut chunks: Vec<(u32, Vec<u8>)> = Vec::new(); // Address, bytes
+    let mut data_records = 0;
+    let mut entry = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut chars = match line.strip_prefix('S') {
+            Some(rest) => rest.chars(),
+            None => return Err(format!("Line {}: not an S-record", line_number)),
+        };
+        let record_type = chars.next().unwrap_or(' ');
+        let hex = chars.as_str();
+
+        let bytes = parse_hex(hex).map_err(|err| format!("Line {}: {}", line_number, err))?;
+        if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(format!("Line {}: record length doesn't match its contents", line_number));
+        }
+
+        let sum = bytes[..bytes.len() - 1].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if !sum != bytes[bytes.len() - 1] {
+            return Err(format!("Line {}: checksum mismatch (expected {:02X}, found {:02X})", line_number, !sum, bytes[bytes.len() - 1]));
+        }
+
+        let address_size = match record_type {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(format!("Line {}: invalid record type S{}", line_number, record_type)),
+        };
+
+        if bytes.len() < address_size + 2 {
+            return Err(format!("Line {}: record is too short for its address", line_number));
+        }
+
+        let address = bytes[1..=address_size].iter().fold(0u32, |address, byte| (address << 8) | *byte as u32);
+        let data = &bytes[address_size + 1..bytes.len() - 1];
+
+        match record_type {
+            '0' => {},
+            '1' | '2' | '3' => {
+                if !(data.len() as u32).is_multiple_of(unit) {
+                    return Err(format!("Line {}: record doesn't hold whole address units", line_number));
+                }
+
+                match chunks.last_mut() {
+                    Some((start, bytes)) if *start + bytes.len() as u32 / unit == address => bytes.extend(data),
+                    _ => chunks.push((address, data.to_vec())),
+                }
+                data_records += 1;
+            },
+            '5' | '6' => {
+                if address != data_records {
+                    return Err(format!("Line {}: record count is {}, but {} data records were read", line_number, address, data_records));
+                }
+            },
+            _ => {
+                entry = Some(address);
+                break;
+            },
+        }
+    }
+
+    Program::from_chunks(chunks, entry, endian, addressing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(origin: u32, addressing: Addressing) -> Program {
+        let segments = vec![
+            Segment::new(origin, vec![0x1122_3344, 0x5566_7788, 0x99AA_BBCC]),
+            Segment::new(origin + 0x20, vec![1, 2, 3, 4, 5]),
+        ];
+        Program::new(segments, origin + 4, Endian::Big, addressing)
+    }
+
+    fn record_types(text: &str) -> Vec<&str> {
+        text.lines().map(|line| &line[..2]).collect()
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        for origin in [0x100, 0x1_0000, 0x100_0000] {
+            for addressing in [Addressing::Byte, Addressing::Word] {
+                for endian in [Endian::Big, Endian::Little] {
+                    let mut original = program(origin, addressing);
+                    original.endian = endian;
+
+                    let read = read(&write(&original), endian, addressing).unwrap();
+                    assert_eq!(read.segments, original.segments);
+                    assert_eq!(read.entry, original.entry);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn address_width_fits_highest_address() {
+        let types = |origin| record_types(&write(&program(origin, Addressing::Byte))).join(" ");
+
+        // Header, 1 + 2 data records, count, start address
+        assert_eq!(types(0x100), "S0 S1 S1 S1 S5 S9");
+        assert_eq!(types(0xFF00), "S0 S1 S1 S1 S5 S9");
+        assert_eq!(types(0xFFF0), "S0 S2 S2 S2 S5 S8");
+        assert_eq!(types(0x1_0000), "S0 S2 S2 S2 S5 S8");
+        assert_eq!(types(0xFF_FFD0), "S0 S3 S3 S3 S5 S7");
+        assert_eq!(types(0x100_0000), "S0 S3 S3 S3 S5 S7");
+    }
+
+    #[test]
+    fn record_count_is_checked() {
+        let data = record(1, 0x0000, 2, &[0x01, 0x02, 0x03, 0x04]) + &record(1, 0x0004, 2, &[0x05, 0x06, 0x07, 0x08]);
+
+        let text = data.clone() + &record(5, 2, 2, &[]) + &record(9, 0, 2, &[]);
+        assert!(read(&text, Endian::Big, Addressing::Byte).is_ok());
+
+        let text = data.clone() + &record(6, 2, 3, &[]) + &record(9, 0, 2, &[]);
+        assert!(read(&text, Endian::Big, Addressing::Byte).is_ok());
+
+        let text = data.clone() + &record(5, 3, 2, &[]) + &record(9, 0, 2, &[]);
+        let err = read(&text, Endian::Big, Addressing::Byte).unwrap_err();
+        assert_eq!(err, "Line 3: record count is 3, but 2 data records were read");
+
+        let text = data + &record(6, 1, 3, &[]);
+        let err = read(&text, Endian::Big, Addressing::Byte).unwrap_err();
+        assert_eq!(err, "Line 3: record count is 1, but 2 data records were read");
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        // S0 header "HDR", one data record, and a count of 1
+        let text = "S00600004844521B\nS1070000DEADBEEFC0\nS5030001FB\nS9030000FC\n";
+        assert!(read(text, Endian::Big, Addressing::Byte).is_ok());
+
+        // The header is checked even though its contents are ignored
+        let err = read(&text.replace("521B", "521C"), Endian::Big, Addressing::Byte).unwrap_err();
+        assert_eq!(err, "Line 1: checksum mismatch (expected 1B, found 1C)");
+
+        // The count is covered by the checksum, so a changed count fails before it's compared
+        let err = read(&text.replace("S5030001FB", "S5030002FB"), Endian::Big, Addressing::Byte).unwrap_err();
+        assert_eq!(err, "Line 3: checksum mismatch (expected FA, found FB)");
+    }
+}
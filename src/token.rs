@@ -1,14 +1,19 @@
-use crate::instructions::{Instructions, InstructionMode};
+use crate::ast::Span;
 
 
-#[derive(Debug)]
-pub enum Token{
-    OpCode(Instructions),
-    Mode(InstructionMode),
-    Register(u32),
-    BaseOffset(u32, u32),
-    Value(u32),
-    //Memory(u32),
-    Label(String),
-    None,
-}
\ No newline at end of file
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Identifier(String), // Mnemonics, registers, labels and directives (with their leading '.')
+    Number(u64),        // Up to 64 bits, the parser checks it fits where it's used
+    Float(f64),         // Has a '.', like 1.5 or 2.0e-3
+    String(String),     // Escapes are already replaced
+    Punctuation(char),  // , : [ ] + - ( ) = ! < >
+    Comment(String),    // From ';' to the end of the line, without the ';'
+    Newline,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
@@ -40,17 +40,6 @@ macro_rules! enum_conv_gen {
     }
 }
 
-#[derive(Debug)]
-pub struct Parameter{
-    pub value: u32,
-}
-
-impl Parameter{
-    pub fn get_value(&self) -> u32{
-        self.value
-    }
-}
-
 pub fn register_to_byte(reg: &str) -> Option<u32> {
     let value = reg.to_uppercase();
     match value.as_str() {
@@ -74,69 +63,61 @@ pub fn register_to_byte(reg: &str) -> Option<u32> {
     }
 }
 
-pub fn cleanup_line(line: &str) -> Vec<String> {
-    let mut cleaned_line = String::new();
-    // This is an asm thing. First thing to do, is to remove all comments
-    // Read the line up until a comment is found (;)
-    for c in line.chars() {
-        if c == ';' {
-            break;
-        }
-        cleaned_line.push(c);
-    }
-
-    // Now we need to remove all commas. They are not needed in the assembly,
-    // but can help with readability
-    cleaned_line = cleaned_line.replace(",", "");
-
-    // Now split the line into tokens. We split the line by spaces
-    // This will give us a vector of tokens
-    let tokens: Vec<&str> = cleaned_line.split(" ").collect();
-
-    // For each token, we need to remove all whitespace surrounding it
-    // This is done by trimming the token
-    let mut cleaned_tokens = Vec::new();
-    for token in tokens {
-        cleaned_tokens.push(token.trim().to_string());
-    }
-
-    // We should now have a vector of tokens, with no whitespace surrounding them
-    // This is what we need to tokenize the line
-
-    // Remove any empty items ("")
-    cleaned_tokens.retain(|x| x != "");
-
-    cleaned_tokens
+pub fn byte_to_register(reg: u32) -> String {
+    let letter = (b'a' + (reg & 0xF) as u8) as char;
+    format!("R{}", letter)
 }
 
 pub fn string_to_u32(value: &str) -> Option<u32>{
+    string_to_u64(value).and_then(|value| u32::try_from(value).ok())
+}
+
+pub fn string_to_u64(value: &str) -> Option<u64>{
     let value = value.to_lowercase();
     // Check hex
-    if value.contains("0x"){
-        let value = value.replace("0x", "");
+    if let Some(value) = value.strip_prefix("0x") {
+        u64::from_str_radix(value, 16).ok()
+    } else if let Some(value) = value.strip_prefix("0b") {
+        u64::from_str_radix(value, 2).ok()
+    } else {
+        // Decimal
+        value.parse::<u64>().ok()
+    }
+}
 
-        let value = match u32::from_str_radix(&value, 16){
-            Ok(value) => Some(value),
-            Err(_) => None,
-        };
+/// Parses a string of hex digit pairs into bytes (eg: "0AFF" -> [0x0A, 0xFF]).
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return Err(format!("invalid hex digits '{}'", text));
+    }
 
-        value
-    }else if value.contains("0b"){
-        let value = value.replace("0b", "");
+    (0..text.len()).step_by(2).map(|i| {
+        u8::from_str_radix(&text[i..i + 2], 16)
+            .map_err(|_| format!("invalid hex digits '{}'", &text[i..i + 2]))
+    }).collect()
+}
+
+/// CRC-32 (IEEE 802.3, the one used by zip and PNG) of the given bytes.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
 
-        let value = match u32::from_str_radix(&value, 2){
-            Ok(value) => Some(value),
-            Err(_) => None,
-        };
+    !crc
+}
 
-        value
-    }else{
-        // Decimal
-        let value = match value.parse::<u32>(){
-            Ok(value) => Some(value),
-            Err(_) => None,
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        value
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
     }
 }
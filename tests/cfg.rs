@@ -0,0 +1,112 @@
+// The control flow graph as other tools see it, through the library
+
+use dbv_compiler::cfg::{ControlFlowGraph, EdgeKind};
+use dbv_compiler::{control, labels, lexer, parser};
+
+fn graph(source: &str) -> ControlFlowGraph {
+    let tokens = lexer::Lexer::new("prog.asm").tokenize(source);
+    let statements = labels::scope_labels(&parser::parse(&tokens), false);
+    ControlFlowGraph::build(&control::lower_control_flow(&statements))
+}
+
+fn edges(graph: &ControlFlowGraph) -> Vec<(String, String, EdgeKind)> {
+    graph.edges.iter().map(|edge| (graph.block_name(edge.from), graph.block_name(edge.to), edge.kind)).collect()
+}
+
+const PROGRAM: &str = concat!(
+    "main: SET Ra, 3\n",
+    "    CALL square\n",
+    "    CMP Ra, 9\n",
+    "    IFE done\n",
+    "    SET Ra, 0\n",
+    "done: HLT\n",
+    "square: MUL Ra, Ra, Ra\n",
+    "    RET\n",
+);
+
+#[test]
+fn blocks_split_at_labels_and_after_jumps() {
+    let graph = graph(PROGRAM);
+
+    let blocks: Vec<(String, Vec<String>)> = graph.blocks.iter().enumerate()
+        .map(|(index, block)| (graph.block_name(index), block.instructions.iter().map(ToString::to_string).collect()))
+        .collect();
+    assert_eq!(blocks, vec![
+        (String::from("MAIN"), vec![String::from("SET Ra, 0x3"), String::from("CALL SQUARE")]),
+        (String::from("block 1"), vec![String::from("CMP Ra, 0x9"), String::from("IFE DONE")]),
+        (String::from("block 2"), vec![String::from("SET Ra, 0x0")]),
+        (String::from("DONE"), vec![String::from("HLT")]),
+        (String::from("SQUARE"), vec![String::from("MUL Ra, Ra, Ra"), String::from("RET")]),
+    ]);
+    assert_eq!(graph.entry, Some(0));
+    assert_eq!(graph.labels["SQUARE"], 4);
+}
+
+#[test]
+fn edges_follow_jumps_calls_and_returns() {
+    let graph = graph(PROGRAM);
+
+    let edge = |from: &str, to: &str, kind| (String::from(from), String::from(to), kind);
+    let mut found = edges(&graph);
+    found.sort_by_key(|(from, to, _)| (from.clone(), to.clone()));
+    assert_eq!(found, vec![
+        edge("MAIN", "SQUARE", EdgeKind::Call),
+        edge("MAIN", "block 1", EdgeKind::Fallthrough),
+        edge("SQUARE", "block 1", EdgeKind::Return),
+        edge("block 1", "DONE", EdgeKind::Taken),
+        edge("block 1", "block 2", EdgeKind::Fallthrough),
+        edge("block 2", "DONE", EdgeKind::Fallthrough),
+    ]);
+
+    // HLT and RET don't fall through into the next block
+    assert_eq!(graph.fallthrough(3), None);
+    assert_eq!(graph.returns_from(4), vec![4]);
+}
+
+#[test]
+fn jumps_and_data_stop_fallthrough() {
+    let graph = graph(concat!(
+        "main: JMP skip\n",
+        "    HLT\n",
+        "skip: .if Ra == 1\n",
+        "    SET Rb, 1\n",
+        ".endif\n",
+        "    HLT\n",
+        "table: .word 1\n",
+        "after: HLT\n",
+    ));
+
+    // The HLT after the JMP has no way in
+    assert!(graph.predecessors(1).next().is_none());
+    assert_eq!(graph.successors(0).map(|edge| edge.kind).collect::<Vec<_>>(), vec![EdgeKind::Taken]);
+
+    // `.if` becomes CMP and IFN around the block it skips
+    let skip = graph.labels["SKIP"];
+    assert_eq!(graph.blocks[skip].last().to_string(), "IFN #if0.else");
+
+    // The block before `.word` doesn't run into the code after it
+    let after = graph.labels["AFTER"];
+    assert!(graph.predecessors(after).next().is_none());
+}
+
+#[test]
+fn dot_output() {
+    let graph = graph(concat!(
+        "main: CMP Ra, 0\n",
+        "    IFE done\n",
+        "    SET Ra, 1\n",
+        "done: HLT\n",
+    ));
+
+    assert_eq!(graph.to_dot("prog.asm"), concat!(
+        "digraph \"prog.asm\" {\n",
+        "    node [shape=box, fontname=\"monospace\"];\n",
+        "    b0 [label=\"MAIN:\\l    CMP Ra, 0x0\\l    IFE DONE\\l\", penwidth=2];\n",
+        "    b1 [label=\"block 1:\\l    SET Ra, 0x1\\l\"];\n",
+        "    b2 [label=\"DONE:\\l    HLT\\l\"];\n",
+        "    b0 -> b2 [label=\"taken\", style=solid];\n",
+        "    b0 -> b1 [label=\"fallthrough\", style=dashed];\n",
+        "    b1 -> b2 [label=\"fallthrough\", style=dashed];\n",
+        "}\n",
+    ));
+}